@@ -0,0 +1,41 @@
+//! Access token refresh
+//!
+//! Access tokens used by FDWs to authenticate to the remote source can expire
+//! in the middle of a session. An extension can register a [`TokenRefresher`]
+//! using [`set_token_refresher`], for example from its `_PG_init()`. When a
+//! remote request is rejected because the token has expired, the FDW calls
+//! [`refresh_token`] with the token name configured on the foreign server to get
+//! a new token, and then retries the request once with it.
+//!
+//! The refresher is registered in each backend and shared by all the FDWs.
+//!
+//! ```rust,no_run
+//! use supabase_wrappers::auth;
+//!
+//! fn refresh(fdw_name: &str, token_name: &str) -> Option<String> {
+//!     // fetch a new token from the identity provider
+//!     Some(format!("new token of {} for {}", token_name, fdw_name))
+//! }
+//!
+//! auth::set_token_refresher(Some(refresh));
+//! ```
+use std::sync::RwLock;
+
+/// Token refresher which is called with the FDW name and the token name, and
+/// returns the new token, or `None` if the token cannot be refreshed
+pub type TokenRefresher = fn(fdw_name: &str, token_name: &str) -> Option<String>;
+
+static TOKEN_REFRESHER: RwLock<Option<TokenRefresher>> = RwLock::new(None);
+
+/// Register the token refresher, pass `None` to remove it
+pub fn set_token_refresher(refresher: Option<TokenRefresher>) {
+    *TOKEN_REFRESHER.write().unwrap() = refresher;
+}
+
+/// Get a new token of `token_name` for the FDW from the registered refresher
+///
+/// Returns `None` if no refresher is registered or it cannot refresh the token.
+pub fn refresh_token(fdw_name: &str, token_name: &str) -> Option<String> {
+    let refresher = *TOKEN_REFRESHER.read().unwrap();
+    refresher.and_then(|refresh| refresh(fdw_name, token_name))
+}
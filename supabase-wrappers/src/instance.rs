@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::prelude::*;
 use pgrx::pg_sys::panic::ErrorReport;
 use pgrx::prelude::*;
@@ -9,6 +10,6 @@ pub(super) unsafe fn create_fdw_instance<E: Into<ErrorReport>, W: ForeignDataWra
     let ftable = pg_sys::GetForeignTable(ftable_id);
     let fserver = pg_sys::GetForeignServer((*ftable).serverid);
     let fserver_opts = options_to_hashmap((*fserver).options).report_unwrap();
-    let wrapper = W::new(&fserver_opts);
+    let wrapper = metrics::count_error::<E, W, _>(W::new(&fserver_opts));
     wrapper.report_unwrap()
 }
@@ -1,16 +1,19 @@
 //! Provides interface types and trait to develop Postgres foreign data wrapper
 //!
 
+use crate::metrics::ErrorClass;
 use crate::FdwRoutine;
 use pgrx::pg_sys::panic::ErrorReport;
-use pgrx::prelude::{Date, Timestamp};
+use pgrx::prelude::{Date, Timestamp, TimestampWithTimeZone};
 use pgrx::{
-    fcinfo,
+    enum_helper, fcinfo,
     pg_sys::{self, Datum, Oid},
+    tupdesc::PgTupleDesc,
     AllocatedByRust, AnyNumeric, FromDatum, IntoDatum, JsonB, PgBuiltInOids, PgOid,
 };
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::ffi::CStr;
+use std::ffi::{c_char, CStr, CString};
 use std::fmt;
 use std::iter::Zip;
 use std::mem;
@@ -44,7 +47,28 @@ pub enum Cell {
     String(String),
     Date(Date),
     Timestamp(Timestamp),
+    Timestamptz(TimestampWithTimeZone),
     Json(JsonB),
+    Bytea(Vec<u8>),
+    BoolArray(Vec<Option<bool>>),
+    I16Array(Vec<Option<i16>>),
+    I32Array(Vec<Option<i32>>),
+    I64Array(Vec<Option<i64>>),
+    F32Array(Vec<Option<f32>>),
+    F64Array(Vec<Option<f64>>),
+    StringArray(Vec<Option<String>>),
+    /// composite type value in its text form, such as `(foo,42)`, with the
+    /// composite type OID
+    Composite(Oid, String),
+    /// multidimensional array value in its text form, such as `{{1,2},{3,4}}`,
+    /// with the array type OID
+    MdArray(Oid, String),
+    /// range value in its text form, such as `[1,5)`, with the range type OID
+    Range(Oid, String),
+    /// enum value by its label, with the enum type OID
+    Enum(Oid, String),
+    /// uuid value in its text form, such as `a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11`
+    Uuid(String),
 }
 
 impl Clone for Cell {
@@ -61,7 +85,21 @@ impl Clone for Cell {
             Cell::String(v) => Cell::String(v.clone()),
             Cell::Date(v) => Cell::Date(*v),
             Cell::Timestamp(v) => Cell::Timestamp(*v),
+            Cell::Timestamptz(v) => Cell::Timestamptz(*v),
             Cell::Json(v) => Cell::Json(JsonB(v.0.clone())),
+            Cell::Bytea(v) => Cell::Bytea(v.clone()),
+            Cell::BoolArray(v) => Cell::BoolArray(v.clone()),
+            Cell::I16Array(v) => Cell::I16Array(v.clone()),
+            Cell::I32Array(v) => Cell::I32Array(v.clone()),
+            Cell::I64Array(v) => Cell::I64Array(v.clone()),
+            Cell::F32Array(v) => Cell::F32Array(v.clone()),
+            Cell::F64Array(v) => Cell::F64Array(v.clone()),
+            Cell::StringArray(v) => Cell::StringArray(v.clone()),
+            Cell::Composite(typoid, v) => Cell::Composite(*typoid, v.clone()),
+            Cell::MdArray(typoid, v) => Cell::MdArray(*typoid, v.clone()),
+            Cell::Range(typoid, v) => Cell::Range(*typoid, v.clone()),
+            Cell::Enum(typoid, v) => Cell::Enum(*typoid, v.clone()),
+            Cell::Uuid(v) => Cell::Uuid(v.clone()),
         }
     }
 }
@@ -76,7 +114,7 @@ impl fmt::Display for Cell {
             Cell::I32(v) => write!(f, "{}", v),
             Cell::F64(v) => write!(f, "{}", v),
             Cell::I64(v) => write!(f, "{}", v),
-            Cell::Numeric(v) => write!(f, "{:?}", v),
+            Cell::Numeric(v) => write!(f, "{}", v),
             Cell::String(v) => write!(f, "'{}'", v),
             Cell::Date(v) => unsafe {
                 let dt =
@@ -94,11 +132,70 @@ impl fmt::Display for Cell {
                 let ts_cstr = CStr::from_ptr(ts.cast_mut_ptr());
                 write!(f, "'{}'", ts_cstr.to_str().unwrap())
             },
+            Cell::Timestamptz(v) => unsafe {
+                let ts = fcinfo::direct_function_call_as_datum(
+                    pg_sys::timestamptz_out,
+                    &[(*v).into_datum()],
+                )
+                .unwrap();
+                let ts_cstr = CStr::from_ptr(ts.cast_mut_ptr());
+                write!(f, "'{}'", ts_cstr.to_str().unwrap())
+            },
             Cell::Json(v) => write!(f, "{:?}", v),
+            Cell::Bytea(v) => {
+                let hex = v.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+                write!(f, "'\\x{}'", hex)
+            }
+            Cell::BoolArray(v) => write_array(v, f),
+            Cell::I16Array(v) => write_array(v, f),
+            Cell::I32Array(v) => write_array(v, f),
+            Cell::I64Array(v) => write_array(v, f),
+            Cell::F32Array(v) => write_array(v, f),
+            Cell::F64Array(v) => write_array(v, f),
+            Cell::StringArray(v) => {
+                let v: Vec<Option<String>> = v
+                    .iter()
+                    .map(|e| e.as_ref().map(|s| format!("'{}'", s)))
+                    .collect();
+                write_array(&v, f)
+            }
+            Cell::Composite(_, v) => write!(f, "'{}'", v),
+            Cell::MdArray(_, v) => write!(f, "'{}'", v),
+            Cell::Range(_, v) => write!(f, "'{}'", v),
+            Cell::Enum(_, v) => write!(f, "'{}'", v),
+            Cell::Uuid(v) => write!(f, "'{}'", v),
         }
     }
 }
 
+// write array elements as a bracketed list, null element is written as 'null'
+fn write_array<T: fmt::Display>(array: &[Option<T>], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let elems = array
+        .iter()
+        .map(|e| match e {
+            Some(v) => v.to_string(),
+            None => "null".to_owned(),
+        })
+        .collect::<Vec<String>>();
+    write!(f, "[{}]", elems.join(","))
+}
+
+// build a datum from its text form by the type's input function
+fn text_to_datum(typoid: Oid, v: String) -> Option<Datum> {
+    let v = CString::new(v).ok()?;
+    unsafe {
+        let mut typinput = Oid::INVALID;
+        let mut typioparam = Oid::INVALID;
+        pg_sys::getTypeInputInfo(typoid, &mut typinput, &mut typioparam);
+        Some(pg_sys::OidInputFunctionCall(
+            typinput,
+            v.as_ptr() as *mut c_char,
+            typioparam,
+            -1,
+        ))
+    }
+}
+
 impl IntoDatum for Cell {
     fn into_datum(self) -> Option<Datum> {
         match self {
@@ -113,7 +210,21 @@ impl IntoDatum for Cell {
             Cell::String(v) => v.into_datum(),
             Cell::Date(v) => v.into_datum(),
             Cell::Timestamp(v) => v.into_datum(),
+            Cell::Timestamptz(v) => v.into_datum(),
             Cell::Json(v) => v.into_datum(),
+            Cell::Bytea(v) => v.into_datum(),
+            Cell::BoolArray(v) => v.into_datum(),
+            Cell::I16Array(v) => v.into_datum(),
+            Cell::I32Array(v) => v.into_datum(),
+            Cell::I64Array(v) => v.into_datum(),
+            Cell::F32Array(v) => v.into_datum(),
+            Cell::F64Array(v) => v.into_datum(),
+            Cell::StringArray(v) => v.into_datum(),
+            Cell::Composite(typoid, v) => text_to_datum(typoid, v),
+            Cell::MdArray(typoid, v) => text_to_datum(typoid, v),
+            Cell::Range(typoid, v) => text_to_datum(typoid, v),
+            Cell::Enum(typoid, v) => text_to_datum(typoid, v),
+            Cell::Uuid(v) => text_to_datum(pg_sys::UUIDOID, v),
         }
     }
 
@@ -132,9 +243,22 @@ impl IntoDatum for Cell {
             || other == pg_sys::INT8OID
             || other == pg_sys::NUMERICOID
             || other == pg_sys::TEXTOID
+            || other == pg_sys::XMLOID
             || other == pg_sys::DATEOID
             || other == pg_sys::TIMESTAMPOID
+            || other == pg_sys::TIMESTAMPTZOID
             || other == pg_sys::JSONBOID
+            || other == pg_sys::BYTEAOID
+            || other == pg_sys::BOOLARRAYOID
+            || other == pg_sys::INT2ARRAYOID
+            || other == pg_sys::INT4ARRAYOID
+            || other == pg_sys::INT8ARRAYOID
+            || other == pg_sys::FLOAT4ARRAYOID
+            || other == pg_sys::FLOAT8ARRAYOID
+            || other == pg_sys::TEXTARRAYOID
+            || other == pg_sys::UUIDOID
+            || is_composite_type(other)
+            || is_enum_type(other)
     }
 }
 
@@ -172,6 +296,15 @@ impl FromDatum for Cell {
             PgOid::BuiltIn(PgBuiltInOids::NUMERICOID) => {
                 Some(Cell::Numeric(AnyNumeric::from_datum(datum, false).unwrap()))
             }
+            PgOid::BuiltIn(PgBuiltInOids::MONEYOID) => {
+                // money is stored as integer cents, convert it to numeric so it
+                // keeps its decimal value when passed to the foreign side
+                let value = fcinfo::direct_function_call::<AnyNumeric>(
+                    pg_sys::cash_numeric,
+                    &[Some(datum)],
+                );
+                Some(Cell::Numeric(value.unwrap()))
+            }
             PgOid::BuiltIn(PgBuiltInOids::TEXTOID) => {
                 Some(Cell::String(String::from_datum(datum, false).unwrap()))
             }
@@ -181,14 +314,132 @@ impl FromDatum for Cell {
             PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPOID) => Some(Cell::Timestamp(
                 Timestamp::from_datum(datum, false).unwrap(),
             )),
+            PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPTZOID) => Some(Cell::Timestamptz(
+                TimestampWithTimeZone::from_datum(datum, false).unwrap(),
+            )),
             PgOid::BuiltIn(PgBuiltInOids::JSONBOID) => {
                 Some(Cell::Json(JsonB::from_datum(datum, false).unwrap()))
             }
+            PgOid::BuiltIn(PgBuiltInOids::BYTEAOID) => {
+                Some(Cell::Bytea(Vec::<u8>::from_datum(datum, false).unwrap()))
+            }
+            PgOid::BuiltIn(PgBuiltInOids::BOOLARRAYOID) => Some(Cell::BoolArray(
+                Vec::<Option<bool>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::INT2ARRAYOID) => Some(Cell::I16Array(
+                Vec::<Option<i16>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::INT4ARRAYOID) => Some(Cell::I32Array(
+                Vec::<Option<i32>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::INT8ARRAYOID) => Some(Cell::I64Array(
+                Vec::<Option<i64>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::FLOAT4ARRAYOID) => Some(Cell::F32Array(
+                Vec::<Option<f32>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::FLOAT8ARRAYOID) => Some(Cell::F64Array(
+                Vec::<Option<f64>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::TEXTARRAYOID) => Some(Cell::StringArray(
+                Vec::<Option<String>>::from_datum(datum, false).unwrap(),
+            )),
+            PgOid::BuiltIn(PgBuiltInOids::UUIDOID) => Some(Cell::Uuid(
+                pgrx::Uuid::from_datum(datum, false).unwrap().to_string(),
+            )),
+            // xml has the same binary representation as text
+            PgOid::BuiltIn(PgBuiltInOids::XMLOID) => {
+                Some(Cell::String(String::from_datum(datum, false).unwrap()))
+            }
+            // citext has the same binary representation as text
+            PgOid::Custom(oid) if is_citext_type(oid) => {
+                Some(Cell::String(String::from_datum(datum, false).unwrap()))
+            }
+            // enum value is the OID of its pg_enum entry
+            PgOid::Custom(oid) if is_enum_type(oid) => {
+                let (label, _, _) = enum_helper::lookup_enum_by_oid(Oid::from_datum(datum, false)?);
+                Some(Cell::Enum(oid, label))
+            }
             _ => None,
         }
     }
 }
 
+/// Check if the type is `citext` provided by the citext extension
+///
+/// The `citext` type is not a built-in type so its OID is not fixed, we check
+/// its type name instead.
+pub fn is_citext_type(typoid: Oid) -> bool {
+    if typoid == Oid::INVALID {
+        return false;
+    }
+    let typname = unsafe { CStr::from_ptr(pg_sys::format_type_be(typoid)) };
+    typname
+        .to_str()
+        .map(|s| s.rsplit('.').next() == Some("citext"))
+        .unwrap_or(false)
+}
+
+/// Check if the type is a composite type, such as one created by
+/// `CREATE TYPE ... AS (...)`
+pub fn is_composite_type(typoid: Oid) -> bool {
+    typoid != Oid::INVALID
+        && unsafe { pg_sys::get_typtype(typoid) } as u8 == pg_sys::TYPTYPE_COMPOSITE
+}
+
+/// Check if the type is an enum type, such as one created by
+/// `CREATE TYPE ... AS ENUM (...)`
+pub fn is_enum_type(typoid: Oid) -> bool {
+    typoid != Oid::INVALID && unsafe { pg_sys::type_is_enum(typoid) }
+}
+
+/// Check if the label is one of the enum type's labels
+pub fn is_enum_label(typoid: Oid, label: &str) -> bool {
+    let Ok(label) = CString::new(label) else {
+        return false;
+    };
+    unsafe {
+        let tup = pg_sys::SearchSysCache2(
+            pg_sys::SysCacheIdentifier_ENUMTYPOIDNAME as _,
+            typoid.into_datum().unwrap(),
+            Datum::from(label.as_ptr()),
+        );
+        if tup.is_null() {
+            return false;
+        }
+        pg_sys::ReleaseSysCache(tup);
+        true
+    }
+}
+
+/// Get the subtype of a range type, such as `integer` of `int4range`
+///
+/// Returns `None` if the type is not a range type.
+pub fn range_subtype(typoid: Oid) -> Option<Oid> {
+    if typoid == Oid::INVALID {
+        return None;
+    }
+    let subtype = unsafe { pg_sys::get_range_subtype(typoid) };
+    (subtype != Oid::INVALID).then_some(subtype)
+}
+
+/// Get the field names of a composite type in their defined order, dropped
+/// fields are excluded
+///
+/// Returns `None` if the type is not a composite type.
+pub fn composite_type_fields(typoid: Oid) -> Option<Vec<String>> {
+    if !is_composite_type(typoid) {
+        return None;
+    }
+    let tup_desc = PgTupleDesc::for_composite_type_by_oid(typoid)?;
+    let fields = tup_desc
+        .iter()
+        .filter(|a| !a.attisdropped)
+        .map(|a| pgrx::name_data_to_str(&a.attname).to_owned())
+        .collect();
+    Some(fields)
+}
+
 /// A data row in a table
 ///
 /// The row contains a column name list and cell list with same number of
@@ -200,6 +451,10 @@ pub struct Row {
 
     /// column cell list, should match with cols
     pub cells: Vec<Option<Cell>>,
+
+    // column name buffers of the cleared cells, reused by `push` so scanning
+    // doesn't allocate column names for every row
+    spare_cols: Vec<String>,
 }
 
 impl Row {
@@ -210,10 +465,48 @@ impl Row {
 
     /// Push a cell with column name to this row
     pub fn push(&mut self, col: &str, cell: Option<Cell>) {
-        self.cols.push(col.to_owned());
+        let name = match self.spare_cols.pop() {
+            Some(mut name) => {
+                name.clear();
+                name.push_str(col);
+                name
+            }
+            None => col.to_owned(),
+        };
+        self.cols.push(name);
         self.cells.push(cell);
     }
 
+    /// Return the number of cells in this row
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Return `true` if this row has no cells
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Return the cell at the specified index, or `None` if the index is out of bounds
+    ///
+    /// The returned cell itself is `None` if the column value is null.
+    pub fn get(&self, index: usize) -> Option<&Option<Cell>> {
+        self.cells.get(index)
+    }
+
+    /// Return the cell of the first column with the specified name, or `None`
+    /// if no such column exists
+    ///
+    /// The returned cell itself is `None` if the column value is null.
+    pub fn get_by_name(&self, col: &str) -> Option<&Option<Cell>> {
+        self.cols
+            .iter()
+            .position(|c| c == col)
+            .and_then(|index| self.cells.get(index))
+    }
+
     /// Return a zipped <column_name, cell> iterator
     pub fn iter(&self) -> Zip<Iter<'_, String>, Iter<'_, Option<Cell>>> {
         self.cols.iter().zip(self.cells.iter())
@@ -239,7 +532,9 @@ impl Row {
 
     /// Clear the row, removing all column names and cells
     pub fn clear(&mut self) {
-        self.cols.clear();
+        // keep the column names in reverse order, so they are popped in the
+        // same order when the next row is pushed
+        self.spare_cols.extend(self.cols.drain(..).rev());
         self.cells.clear();
     }
 }
@@ -257,6 +552,10 @@ pub struct Column {
 
     /// column type OID, can be used to match pg_sys::BuiltinOid
     pub type_oid: Oid,
+
+    /// column options defined in `CREATE FOREIGN TABLE`, for example,
+    /// `name text options (foo 'bar')`
+    pub options: HashMap<String, String>,
 }
 
 /// A restiction value used in [`Qual`], either a [`Cell`] or an array of [`Cell`]
@@ -351,6 +650,97 @@ impl Qual {
             }
         }
     }
+
+    /// Evaluate this qual against a cell value locally
+    ///
+    /// Returns `None` if the qual cannot be evaluated, for example the operator
+    /// is `like` or the values have different types, in which case the caller
+    /// should keep the row and leave it to Postgres to filter. A `null` cell
+    /// doesn't satisfy any comparison, same as in Postgres.
+    pub fn apply(&self, cell: Option<&Cell>) -> Option<bool> {
+        match &self.value {
+            Value::Cell(value) => apply_operator(&self.operator, cell, value),
+            Value::Array(values) => {
+                let results = values
+                    .iter()
+                    .map(|value| apply_operator(&self.operator, cell, value));
+                // `= any(...)` is satisfied by any element, `<> all(...)` by all
+                // of them, unknown results make the whole qual unknown
+                let mut result = Some(!self.use_or);
+                for r in results {
+                    match r {
+                        Some(r) if r == self.use_or => return Some(r),
+                        Some(_) => {}
+                        None => result = None,
+                    }
+                }
+                result
+            }
+        }
+    }
+}
+
+// evaluate `cell <operator> value`
+fn apply_operator(operator: &str, cell: Option<&Cell>, value: &Cell) -> Option<bool> {
+    match operator {
+        "is" | "is not" => {
+            let is_match = match value {
+                Cell::String(s) if s == "null" => cell.is_none(),
+                Cell::Bool(b) => matches!(cell, Some(Cell::Bool(v)) if v == b),
+                _ => return None,
+            };
+            Some(is_match == (operator == "is"))
+        }
+        "=" | "<>" | "!=" | "<" | "<=" | ">" | ">=" => {
+            let Some(cell) = cell else {
+                return Some(false);
+            };
+            // strings are ordered by collation in Postgres, so only equality
+            // can be evaluated by comparing bytes
+            if matches!(cell, Cell::String(_)) && !matches!(operator, "=" | "<>" | "!=") {
+                return None;
+            }
+            let ord = compare_cells(cell, value)?;
+            Some(match operator {
+                "=" => ord == Ordering::Equal,
+                "<>" | "!=" => ord != Ordering::Equal,
+                "<" => ord == Ordering::Less,
+                "<=" => ord != Ordering::Greater,
+                ">" => ord == Ordering::Greater,
+                _ => ord != Ordering::Less,
+            })
+        }
+        _ => None,
+    }
+}
+
+// compare two cells of compatible types, integers and floats of different
+// widths are compared as the wider type
+fn compare_cells(a: &Cell, b: &Cell) -> Option<Ordering> {
+    let as_i64 = |c: &Cell| match c {
+        Cell::I8(v) => Some(*v as i64),
+        Cell::I16(v) => Some(*v as i64),
+        Cell::I32(v) => Some(*v as i64),
+        Cell::I64(v) => Some(*v),
+        _ => None,
+    };
+    let as_f64 = |c: &Cell| match c {
+        Cell::F32(v) => Some(*v as f64),
+        Cell::F64(v) => Some(*v),
+        _ => as_i64(c).map(|v| v as f64),
+    };
+    match (a, b) {
+        (Cell::Bool(a), Cell::Bool(b)) => Some(a.cmp(b)),
+        (Cell::Numeric(a), Cell::Numeric(b)) => a.partial_cmp(b),
+        (Cell::String(a), Cell::String(b)) => Some(a.cmp(b)),
+        (Cell::Date(a), Cell::Date(b)) => Some(a.into_inner().cmp(&b.into_inner())),
+        (Cell::Timestamp(a), Cell::Timestamp(b)) => Some(a.into_inner().cmp(&b.into_inner())),
+        (Cell::Timestamptz(a), Cell::Timestamptz(b)) => Some(a.into_inner().cmp(&b.into_inner())),
+        _ => match (as_i64(a), as_i64(b)) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => as_f64(a)?.partial_cmp(&as_f64(b)?),
+        },
+    }
 }
 
 /// Query sort, a.k.a `ORDER BY` clause
@@ -494,6 +884,26 @@ pub trait ForeignDataWrapper<E: Into<ErrorReport>> {
         Ok((0, 0))
     }
 
+    /// Returns if the scan needs all the columns of the foreign table
+    ///
+    /// - `options` - the options defined when `CREATE FOREIGN TABLE`
+    ///
+    /// The columns are only extracted and passed to
+    /// [`set_table_columns`](Self::set_table_columns) if this returns `true`,
+    /// which is `false` by default.
+    fn needs_table_columns(&self, _options: &HashMap<String, String>) -> bool {
+        false
+    }
+
+    /// Called before [`begin_scan`](Self::begin_scan) with all the columns of
+    /// the foreign table, if [`needs_table_columns`](Self::needs_table_columns)
+    /// returns `true`
+    ///
+    /// The scan only gets the columns the query uses, so implement this if the
+    /// whole foreign table is needed, for example to check it against the
+    /// remote schema. Does nothing by default.
+    fn set_table_columns(&mut self, _columns: &[Column]) {}
+
     /// Called when begin executing a foreign scan
     ///
     /// - `quals` - `WHERE` clause pushed down
@@ -516,6 +926,10 @@ pub trait ForeignDataWrapper<E: Into<ErrorReport>> {
     ///
     /// FDW must save fetched foreign data into the [`Row`], or return `None` if no more rows to read.
     ///
+    /// Returning an error aborts the query with that error, so an error in the
+    /// middle of scan must not be returned as `None`, otherwise the remaining
+    /// rows are silently lost.
+    ///
     /// [See more details](https://www.postgresql.org/docs/current/fdw-callbacks.html#FDW-CALLBACKS-SCAN).
     fn iter_scan(&mut self, row: &mut Row) -> Result<Option<()>, E>;
 
@@ -592,6 +1006,25 @@ pub trait ForeignDataWrapper<E: Into<ErrorReport>> {
         Ok(())
     }
 
+    /// Called instead of [`end_modify`](Self::end_modify) when the transaction
+    /// or subtransaction running the table update is aborted
+    ///
+    /// Buffered writes should be discarded and remote connections released
+    /// here. It is called during the abort processing, so it must not raise an
+    /// error.
+    fn abort_modify(&mut self) {}
+
+    /// Returns the class of an error returned by the FDW, which is counted in
+    /// the FDW's [`metrics`](crate::metrics)
+    ///
+    /// Errors are counted as [`ErrorClass::Other`] by default.
+    fn error_class(_err: &E) -> ErrorClass
+    where
+        Self: Sized,
+    {
+        ErrorClass::Other
+    }
+
     /// Returns a FdwRoutine for the FDW
     ///
     /// Not to be used directly, use [`wrappers_fdw`](crate::wrappers_fdw) macro instead.
@@ -692,3 +1125,93 @@ pub trait ForeignDataWrapper<E: Into<ErrorReport>> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_accessors() {
+        let mut row = Row::new();
+        assert_eq!(row.len(), 0);
+        assert!(row.is_empty());
+        assert!(row.get(0).is_none());
+        assert!(row.get_by_name("id").is_none());
+
+        row.push("id", Some(Cell::I64(42)));
+        row.push("name", None);
+        assert_eq!(row.len(), 2);
+        assert!(!row.is_empty());
+
+        assert!(matches!(row.get(0), Some(Some(Cell::I64(42)))));
+        assert!(matches!(row.get(1), Some(None)));
+        assert!(row.get(2).is_none());
+
+        assert!(matches!(row.get_by_name("id"), Some(Some(Cell::I64(42)))));
+        assert!(matches!(row.get_by_name("name"), Some(None)));
+        assert!(row.get_by_name("missing").is_none());
+
+        row.clear();
+        assert!(row.is_empty());
+    }
+
+    #[test]
+    fn row_reuses_column_names() {
+        let cols = (0..100).map(|i| format!("col_{}", i)).collect::<Vec<_>>();
+        let mut row = Row::new();
+        for col in &cols {
+            row.push(col, Some(Cell::I64(1)));
+        }
+        let ptrs = row.cols.iter().map(|c| c.as_ptr()).collect::<Vec<_>>();
+
+        // the next row reuses the column name buffers of the cleared row
+        row.clear();
+        for col in &cols {
+            row.push(col, None);
+        }
+        assert_eq!(row.cols, cols);
+        assert_eq!(
+            row.cols.iter().map(|c| c.as_ptr()).collect::<Vec<_>>(),
+            ptrs
+        );
+    }
+
+    #[test]
+    fn qual_apply() {
+        let qual = |operator: &str, value: Value, use_or: bool| Qual {
+            field: "id".to_string(),
+            operator: operator.to_string(),
+            value,
+            use_or,
+            param: None,
+        };
+
+        let eq = qual("=", Value::Cell(Cell::I32(42)), false);
+        assert_eq!(eq.apply(Some(&Cell::I64(42))), Some(true));
+        assert_eq!(eq.apply(Some(&Cell::I64(1))), Some(false));
+        assert_eq!(eq.apply(None), Some(false));
+        assert_eq!(eq.apply(Some(&Cell::String("42".to_string()))), None);
+
+        let gt = qual(">", Value::Cell(Cell::F64(1.5)), false);
+        assert_eq!(gt.apply(Some(&Cell::I64(2))), Some(true));
+        assert_eq!(gt.apply(Some(&Cell::F32(1.5))), Some(false));
+
+        let is_null = qual("is", Value::Cell(Cell::String("null".to_string())), false);
+        assert_eq!(is_null.apply(None), Some(true));
+        assert_eq!(is_null.apply(Some(&Cell::I64(1))), Some(false));
+
+        let any = qual("=", Value::Array(vec![Cell::I64(1), Cell::I64(2)]), true);
+        assert_eq!(any.apply(Some(&Cell::I64(2))), Some(true));
+        assert_eq!(any.apply(Some(&Cell::I64(3))), Some(false));
+
+        let all = qual("<>", Value::Array(vec![Cell::I64(1), Cell::I64(2)]), false);
+        assert_eq!(all.apply(Some(&Cell::I64(3))), Some(true));
+        assert_eq!(all.apply(Some(&Cell::I64(1))), Some(false));
+
+        // strings are only compared for equality
+        let lt = qual("<", Value::Cell(Cell::String("b".to_string())), false);
+        assert_eq!(lt.apply(Some(&Cell::String("a".to_string()))), None);
+        let like = qual("~~", Value::Cell(Cell::String("a%".to_string())), false);
+        assert_eq!(like.apply(Some(&Cell::String("abc".to_string()))), None);
+    }
+}
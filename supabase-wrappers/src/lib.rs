@@ -271,16 +271,50 @@
 //!          ->  Foreign Scan on hello  (cost=0.00..1.00 rows=1 width=0)
 //!                Filter: (id = 1)
 //!                Wrappers: quals = [Qual { field: "id", operator: "=", value: Cell(I32(1)), use_or: false, param: None }]
-//!                Wrappers: tgts = [Column { name: "id", num: 1, type_oid: 20 }, Column { name: "col", num: 2, type_oid: 25 }]
+//!                Wrappers: tgts = [Column { name: "id", num: 1, type_oid: 20, options: {} }, Column { name: "col", num: 2, type_oid: 25, options: {} }]
 //!                Wrappers: sorts = [Sort { field: "col", field_no: 2, reversed: false, nulls_first: false, collate: None }]
 //!                Wrappers: limit = Some(Limit { count: 1, offset: 0 })
 //! (9 rows)
 //! ```
 //!
+//! ### Synchronous FDW
+//!
+//! The [`interface::ForeignDataWrapper`] trait is synchronous and doesn't require an async runtime. Network-bound FDWs, like ClickHouse or BigQuery, can create one using [`create_async_runtime()`](utils::create_async_runtime) and block on async calls inside the callbacks. For non-network sources, such as local files, embedded databases or in-memory data, just implement the callbacks directly and skip the runtime to avoid its overhead.
+//!
+//! See the [Memory](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/memory_fdw) FDW for an example of synchronous FDW.
+//!
+//! ### Metrics
+//!
+//! FDWs can count connections opened, queries run, rows read and written, and errors by class using the [`metrics`] module, so operators get the same visibility across all wrappers. The counters are kept in shared memory if [`metrics::init()`] is called from `_PG_init()` and the extension is loaded by `shared_preload_libraries`, otherwise they are kept in each backend.
+//!
+//! ```rust,no_run
+//! # use supabase_wrappers::metrics::{self, Metric};
+//! metrics::inc("HelloWorldFdw", Metric::RowsRead, 1);
+//! ```
+//!
+//! ### Concurrency limits
+//!
+//! FDWs can cap how many backends use a remote source at the same time using the named semaphores in the [`semaphore`] module. Like [`metrics`], the slots are shared by all backends only if [`semaphore::init()`] is called from `_PG_init()` and the extension is loaded by `shared_preload_libraries`.
+//!
+//! ```rust,no_run
+//! # use supabase_wrappers::semaphore;
+//! let permit = semaphore::acquire("HelloWorldFdw", 4, None);
+//! ```
+//!
+//! ### Token refresh
+//!
+//! FDWs using expiring access tokens can get a new token from the refresher registered in the [`auth`] module when the remote source rejects the expired one, and retry the request once with it.
+//!
+//! ```rust,no_run
+//! # use supabase_wrappers::auth;
+//! let token = auth::refresh_token("HelloWorldFdw", "my_token");
+//! ```
+//!
 //! ### More FDW Examples
 //!
 //! See more FDW examples which interact with RDBMS or RESTful API.
 //! - [HelloWorld](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/helloworld_fdw): A demo FDW to show how to develop a baisc FDW.
+//! - [Memory](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/memory_fdw): A synchronous FDW which keeps data in memory and supports data read and modify.
 //! - [BigQuery](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/bigquery_fdw): A FDW for Google [BigQuery](https://cloud.google.com/bigquery) which supports data read and modify.
 //! - [Clickhouse](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/clickhouse_fdw): A FDW for [ClickHouse](https://clickhouse.com/) which supports data read and modify.
 //! - [Stripe](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/stripe_fdw): A FDW for [Stripe](https://stripe.com/) API which supports data read and modify.
@@ -288,9 +322,13 @@
 //! - [Airtable](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/airtable_fdw): A FDW for [Airtable](https://airtable.com/) API which supports data read only.
 //! - [S3](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/s3_fdw): A FDW for [AWS S3](https://aws.amazon.com/s3/) which supports data read only.
 //! - [Logflare](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/logflare_fdw): A FDW for [Logflare](https://logflare.app/) which supports data read only.
+//! - [Cassandra](https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/cassandra_fdw): A FDW for [Apache Cassandra](https://cassandra.apache.org/) and [ScyllaDB](https://www.scylladb.com/) which supports data read only.
 
+pub mod auth;
 pub mod interface;
+pub mod metrics;
 pub mod options;
+pub mod semaphore;
 pub mod utils;
 
 /// The prelude includes all necessary imports to make Wrappers work
@@ -0,0 +1,185 @@
+//! Per-wrapper metrics
+//!
+//! Queries run, rows read and written, and errors of every FDW are counted by
+//! the framework, the errors are classified by
+//! [`ForeignDataWrapper::error_class`]. FDWs can record the other counters, such
+//! as connections opened, using [`inc`]. The counters are accumulated per FDW
+//! name, which is the FDW's type name, and can be read using [`snapshot`], for
+//! example to expose them in a set-returning function.
+//!
+//! The counters are kept in shared memory and shared by all backends if [`init`]
+//! is called from the extension's `_PG_init()` and the extension is loaded with
+//! `shared_preload_libraries`, otherwise they are kept in each backend locally.
+//!
+//! ```rust,no_run
+//! use pgrx::prelude::*;
+//! use supabase_wrappers::metrics;
+//!
+//! #[pg_guard]
+//! pub extern "C" fn _PG_init() {
+//!     metrics::init();
+//! }
+//! ```
+use crate::interface::ForeignDataWrapper;
+use heapless::FnvIndexMap;
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, PGRXSharedMemory, PgLwLock, PgSharedMemoryInitialization};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// max length of FDW name stored in shared memory, longer name is truncated
+const MAX_NAME_LEN: usize = 64;
+
+// max number of FDWs can be stored in shared memory, must be power of 2
+const MAX_FDWS: usize = 64;
+
+type FdwName = heapless::String<MAX_NAME_LEN>;
+
+/// Class of errors counted in [`Metric::Errors`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Failed to connect to the remote source
+    Connection,
+    /// Remote query or request failed
+    Query,
+    /// Failed to convert data between the remote source and Postgres
+    Data,
+    /// Any other errors, such as invalid options
+    Other,
+}
+
+/// A metric counted for each FDW
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Number of connections opened to the remote source
+    ConnectionsOpened,
+    /// Number of scans and modifications run on the foreign tables
+    QueriesRun,
+    /// Number of rows read from the foreign tables
+    RowsRead,
+    /// Number of rows inserted, updated or deleted on the foreign tables
+    RowsWritten,
+    /// Number of errors of the class
+    Errors(ErrorClass),
+}
+
+/// Counters of a FDW
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub connections_opened: i64,
+    pub queries_run: i64,
+    pub rows_read: i64,
+    pub rows_written: i64,
+    pub connection_errors: i64,
+    pub query_errors: i64,
+    pub data_errors: i64,
+    pub other_errors: i64,
+}
+
+unsafe impl PGRXSharedMemory for Counters {}
+
+impl Counters {
+    fn inc(&mut self, metric: Metric, value: i64) {
+        let counter = match metric {
+            Metric::ConnectionsOpened => &mut self.connections_opened,
+            Metric::QueriesRun => &mut self.queries_run,
+            Metric::RowsRead => &mut self.rows_read,
+            Metric::RowsWritten => &mut self.rows_written,
+            Metric::Errors(ErrorClass::Connection) => &mut self.connection_errors,
+            Metric::Errors(ErrorClass::Query) => &mut self.query_errors,
+            Metric::Errors(ErrorClass::Data) => &mut self.data_errors,
+            Metric::Errors(ErrorClass::Other) => &mut self.other_errors,
+        };
+        *counter = counter.saturating_add(value);
+    }
+}
+
+static SHARED_METRICS: PgLwLock<FnvIndexMap<FdwName, Counters, MAX_FDWS>> = PgLwLock::new();
+
+// if shared memory is initialized, this is set in postmaster and inherited by
+// the backends
+static SHARED_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static LOCAL_METRICS: Mutex<BTreeMap<String, Counters>> = Mutex::new(BTreeMap::new());
+
+/// Initialize shared memory for the metrics
+///
+/// This must be called from `_PG_init()`, it only takes effect when the extension
+/// is loaded by `shared_preload_libraries`.
+pub fn init() {
+    if unsafe { !pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    pg_shmem_init!(SHARED_METRICS);
+    SHARED_ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn fdw_name(name: &str) -> FdwName {
+    let mut fdw_name = FdwName::new();
+    for c in name.chars() {
+        if fdw_name.push(c).is_err() {
+            break;
+        }
+    }
+    fdw_name
+}
+
+/// Increase a metric of the FDW by `value`
+///
+/// If shared memory is used and it is full, the metric of a new FDW is dropped.
+pub fn inc(fdw_name_str: &str, metric: Metric, value: i64) {
+    if SHARED_ENABLED.load(Ordering::Relaxed) {
+        let name = fdw_name(fdw_name_str);
+        let mut metrics = SHARED_METRICS.exclusive();
+        if let Some(counters) = metrics.get_mut(&name) {
+            counters.inc(metric, value);
+        } else {
+            let mut counters = Counters::default();
+            counters.inc(metric, value);
+            let _ = metrics.insert(name, counters);
+        }
+    } else {
+        let mut metrics = LOCAL_METRICS.lock().unwrap();
+        metrics
+            .entry(fdw_name_str.to_owned())
+            .or_default()
+            .inc(metric, value);
+    }
+}
+
+// name of the FDW which its metrics are counted by
+pub(crate) fn fdw_name_of<W>() -> &'static str {
+    let name = std::any::type_name::<W>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+// count the error returned by the FDW by its class
+pub(crate) fn count_error<E: Into<ErrorReport>, W: ForeignDataWrapper<E>, T>(
+    result: Result<T, E>,
+) -> Result<T, E> {
+    if let Err(err) = &result {
+        inc(fdw_name_of::<W>(), Metric::Errors(W::error_class(err)), 1);
+    }
+    result
+}
+
+/// Get counters of all FDWs, ordered by FDW name
+pub fn snapshot() -> Vec<(String, Counters)> {
+    if SHARED_ENABLED.load(Ordering::Relaxed) {
+        let metrics = SHARED_METRICS.share();
+        let mut result = metrics
+            .iter()
+            .map(|(name, counters)| (name.to_string(), *counters))
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    } else {
+        let metrics = LOCAL_METRICS.lock().unwrap();
+        metrics
+            .iter()
+            .map(|(name, counters)| (name.clone(), *counters))
+            .collect()
+    }
+}
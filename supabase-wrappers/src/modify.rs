@@ -1,10 +1,12 @@
 use pgrx::pg_sys::panic::ErrorReport;
 use pgrx::{
-    debug2, memcxt::PgMemoryContexts, pg_sys::Oid, prelude::*, rel::PgRelation,
-    tupdesc::PgTupleDesc, FromDatum, PgSqlErrorCode,
+    debug2, memcxt::PgMemoryContexts, pg_sys::Oid, prelude::*, register_subxact_callback,
+    register_xact_callback, rel::PgRelation, tupdesc::PgTupleDesc, FromDatum, PgSqlErrorCode,
+    PgSubXactCallbackEvent, PgXactCallbackEvent, SubXactCallbackReceipt, XactCallbackReceipt,
 };
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::mem;
 use std::os::raw::c_int;
 use std::ptr;
 
@@ -12,6 +14,7 @@ use crate::prelude::*;
 
 use super::instance;
 use super::memctx;
+use super::metrics::{self, Metric};
 use super::polyfill;
 use super::utils;
 
@@ -31,9 +34,45 @@ struct FdwModifyState<E: Into<ErrorReport>, W: ForeignDataWrapper<E>> {
     // temporary memory context per foreign table, created under Wrappers root
     // memory context
     tmp_ctx: PgMemoryContexts,
+
+    // callbacks to abort the modify when its transaction or subtransaction is
+    // aborted before the modify ends
+    abort_callbacks: Option<(XactCallbackReceipt, SubXactCallbackReceipt)>,
+
+    // rows written which are not counted in the metrics yet, they are counted
+    // at the end of the modify rather than locking the metrics for each row
+    rows_written: i64,
     _phantom: PhantomData<E>,
 }
 
+impl<E: Into<ErrorReport>, W: ForeignDataWrapper<E>> Drop for FdwModifyState<E, W> {
+    fn drop(&mut self) {
+        self.unregister_abort_callbacks();
+    }
+}
+
+// register the callbacks which call `abort` with the modify state when the
+// transaction, or the subtransaction the modify begins in or any of its parents,
+// is aborted
+unsafe fn register_abort_callbacks(
+    state: usize,
+    abort: unsafe fn(usize),
+) -> (XactCallbackReceipt, SubXactCallbackReceipt) {
+    let subxact_id = pg_sys::GetCurrentSubTransactionId();
+    let xact = register_xact_callback(PgXactCallbackEvent::Abort, move || abort(state));
+    let subxact = register_subxact_callback(
+        PgSubXactCallbackEvent::AbortSub,
+        move |aborted_subxact_id, _| {
+            // subtransaction ids are assigned in increasing order, so a
+            // subtransaction started after the aborted one is its child
+            if subxact_id >= aborted_subxact_id {
+                abort(state);
+            }
+        },
+    );
+    (xact, subxact)
+}
+
 impl<E: Into<ErrorReport>, W: ForeignDataWrapper<E>> FdwModifyState<E, W> {
     unsafe fn new(foreigntableid: Oid, tmp_ctx: PgMemoryContexts) -> Self {
         Self {
@@ -43,28 +82,85 @@ impl<E: Into<ErrorReport>, W: ForeignDataWrapper<E>> FdwModifyState<E, W> {
             rowid_typid: Oid::INVALID,
             opts: HashMap::new(),
             tmp_ctx,
+            abort_callbacks: None,
+            rows_written: 0,
             _phantom: PhantomData,
         }
     }
 
+    // register the abort callbacks of the modify. The state lives in a memory
+    // context under CacheMemoryContext which is not reset by the abort, and the
+    // callbacks are unregistered when the state is dropped, so it's still valid
+    // when they are called.
+    unsafe fn register_abort_callbacks(&mut self) {
+        unsafe fn abort<E: Into<ErrorReport>, W: ForeignDataWrapper<E>>(state: usize) {
+            (*(state as *mut FdwModifyState<E, W>))
+                .instance
+                .abort_modify();
+        }
+
+        self.unregister_abort_callbacks();
+        self.abort_callbacks = Some(register_abort_callbacks(
+            self as *mut Self as usize,
+            abort::<E, W>,
+        ));
+    }
+
+    fn unregister_abort_callbacks(&mut self) {
+        if let Some((xact, subxact)) = self.abort_callbacks.take() {
+            xact.unregister_callback();
+            subxact.unregister_callback();
+        }
+    }
+
     fn begin_modify(&mut self) -> Result<(), E> {
-        self.instance.begin_modify(&self.opts)
+        metrics::inc(metrics::fdw_name_of::<W>(), Metric::QueriesRun, 1);
+        metrics::count_error::<E, W, _>(self.instance.begin_modify(&self.opts))
     }
 
     fn insert(&mut self, row: &Row) -> Result<(), E> {
-        self.instance.insert(row)
+        let result = self.instance.insert(row);
+        self.count_row_written(result)
     }
 
     fn update(&mut self, rowid: &Cell, new_row: &Row) -> Result<(), E> {
-        self.instance.update(rowid, new_row)
+        let result = self.instance.update(rowid, new_row);
+        self.count_row_written(result)
     }
 
     fn delete(&mut self, rowid: &Cell) -> Result<(), E> {
-        self.instance.delete(rowid)
+        let result = self.instance.delete(rowid);
+        self.count_row_written(result)
     }
 
     fn end_modify(&mut self) -> Result<(), E> {
-        self.instance.end_modify()
+        let result = metrics::count_error::<E, W, _>(self.instance.end_modify());
+        self.count_rows_written();
+        result
+    }
+
+    fn count_row_written(&mut self, result: Result<(), E>) -> Result<(), E> {
+        match result {
+            Ok(()) => {
+                self.rows_written += 1;
+                Ok(())
+            }
+            Err(err) => {
+                self.count_rows_written();
+                metrics::count_error::<E, W, _>(Err(err))
+            }
+        }
+    }
+
+    fn count_rows_written(&mut self) {
+        if self.rows_written > 0 {
+            let rows_written = mem::take(&mut self.rows_written);
+            metrics::inc(
+                metrics::fdw_name_of::<W>(),
+                Metric::RowsWritten,
+                rows_written,
+            );
+        }
     }
 }
 
@@ -205,6 +301,7 @@ pub(super) extern "C" fn begin_foreign_modify<E: Into<ErrorReport>, W: ForeignDa
             pg_sys::ExecFindJunkAttributeInTlist((*subplan).targetlist, rowid_name_c);
 
         state.begin_modify().report_unwrap();
+        state.register_abort_callbacks();
 
         (*rinfo).ri_FdwState = state.into_pg() as _;
     }
@@ -306,6 +403,7 @@ pub(super) extern "C" fn end_foreign_modify<E: Into<ErrorReport>, W: ForeignData
         if !fdw_state.is_null() {
             let mut state = PgBox::<FdwModifyState<E, W>>::from_pg(fdw_state);
             state.end_modify().report_unwrap();
+            state.unregister_abort_callbacks();
         }
     }
 }
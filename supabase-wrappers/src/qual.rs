@@ -48,6 +48,9 @@ pub(crate) unsafe fn form_array_from_datum(
         PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPARRAYOID) => {
             Vec::<Cell>::from_polymorphic_datum(datum, false, pg_sys::TIMESTAMPOID)
         }
+        PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPTZARRAYOID) => {
+            Vec::<Cell>::from_polymorphic_datum(datum, false, pg_sys::TIMESTAMPTZOID)
+        }
         PgOid::BuiltIn(PgBuiltInOids::JSONBARRAYOID) => {
             Vec::<Cell>::from_polymorphic_datum(datum, false, pg_sys::JSONBOID)
         }
@@ -1,10 +1,11 @@
 use pgrx::FromDatum;
 use pgrx::{
-    debug2, memcxt::PgMemoryContexts, pg_sys::Datum, pg_sys::Oid, prelude::*, IntoDatum,
-    PgSqlErrorCode,
+    debug2, list::PgList, memcxt::PgMemoryContexts, pg_sys::Datum, pg_sys::Oid, prelude::*,
+    IntoDatum, PgSqlErrorCode,
 };
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::mem;
 
 use pgrx::pg_sys::panic::ErrorReport;
 use std::os::raw::c_int;
@@ -14,6 +15,7 @@ use crate::instance;
 use crate::interface::{Cell, Column, Limit, Qual, Row, Sort, Value};
 use crate::limit::*;
 use crate::memctx;
+use crate::metrics::{self, Metric};
 use crate::options::options_to_hashmap;
 use crate::polyfill;
 use crate::prelude::ForeignDataWrapper;
@@ -49,6 +51,10 @@ struct FdwState<E: Into<ErrorReport>, W: ForeignDataWrapper<E>> {
     values: Vec<Datum>,
     nulls: Vec<bool>,
     row: Row,
+
+    // rows read which are not counted in the metrics yet, they are counted at
+    // the end of the scan rather than locking the metrics for each row
+    rows_read: i64,
     _phantom: PhantomData<E>,
 }
 
@@ -65,45 +71,62 @@ impl<E: Into<ErrorReport>, W: ForeignDataWrapper<E>> FdwState<E, W> {
             values: Vec::new(),
             nulls: Vec::new(),
             row: Row::new(),
+            rows_read: 0,
             _phantom: PhantomData,
         }
     }
 
     #[inline]
     fn get_rel_size(&mut self) -> Result<(i64, i32), E> {
-        self.instance.get_rel_size(
+        metrics::count_error::<E, W, _>(self.instance.get_rel_size(
             &self.quals,
             &self.tgts,
             &self.sorts,
             &self.limit,
             &self.opts,
-        )
+        ))
     }
 
     #[inline]
     fn begin_scan(&mut self) -> Result<(), E> {
-        self.instance.begin_scan(
+        metrics::inc(metrics::fdw_name_of::<W>(), Metric::QueriesRun, 1);
+        metrics::count_error::<E, W, _>(self.instance.begin_scan(
             &self.quals,
             &self.tgts,
             &self.sorts,
             &self.limit,
             &self.opts,
-        )
+        ))
     }
 
     #[inline]
     fn iter_scan(&mut self) -> Result<Option<()>, E> {
-        self.instance.iter_scan(&mut self.row)
+        let result = metrics::count_error::<E, W, _>(self.instance.iter_scan(&mut self.row));
+        match result {
+            Ok(Some(_)) => self.rows_read += 1,
+            _ => self.count_rows_read(),
+        }
+        result
     }
 
     #[inline]
     fn re_scan(&mut self) -> Result<(), E> {
-        self.instance.re_scan()
+        self.count_rows_read();
+        metrics::inc(metrics::fdw_name_of::<W>(), Metric::QueriesRun, 1);
+        metrics::count_error::<E, W, _>(self.instance.re_scan())
     }
 
     #[inline]
     fn end_scan(&mut self) -> Result<(), E> {
-        self.instance.end_scan()
+        self.count_rows_read();
+        metrics::count_error::<E, W, _>(self.instance.end_scan())
+    }
+
+    fn count_rows_read(&mut self) {
+        if self.rows_read > 0 {
+            let rows_read = mem::take(&mut self.rows_read);
+            metrics::inc(metrics::fdw_name_of::<W>(), Metric::RowsRead, rows_read);
+        }
     }
 }
 
@@ -133,8 +156,13 @@ pub(super) extern "C" fn get_foreign_rel_size<E: Into<ErrorReport>, W: ForeignDa
         // extract sort list
         state.sorts = extract_sorts(root, baserel, foreigntableid);
 
-        // extract limit
-        state.limit = extract_limit(root, baserel, foreigntableid);
+        // extract limit, it cannot be pushed down if some restrictions are not
+        // extracted as quals, because they are only evaluated locally after
+        // the limit is applied on remote
+        let conds = PgList::<pg_sys::RestrictInfo>::from_pg((*baserel).baserestrictinfo);
+        if state.quals.len() == conds.len() {
+            state.limit = extract_limit(root, baserel, foreigntableid);
+        }
 
         // get foreign table options
         let ftable = pg_sys::GetForeignTable(foreigntableid);
@@ -306,9 +334,13 @@ pub(super) extern "C" fn begin_foreign_scan<E: Into<ErrorReport>, W: ForeignData
 
         // begin scan if it is not EXPLAIN statement
         if eflags & pg_sys::EXEC_FLAG_EXPLAIN_ONLY as c_int <= 0 {
+            let rel = scan_state.ss_currentRelation;
+            if state.instance.needs_table_columns(&state.opts) {
+                let columns = utils::extract_table_columns(rel);
+                state.instance.set_table_columns(&columns);
+            }
             state.begin_scan().report_unwrap();
 
-            let rel = scan_state.ss_currentRelation;
             let tup_desc = (*rel).rd_att;
             let natts = (*tup_desc).natts as usize;
 
@@ -0,0 +1,186 @@
+//! Named counting semaphores
+//!
+//! FDWs can limit how many backends use a remote source at the same time, for
+//! example to cap concurrent scans on a shared server. A slot of the named
+//! semaphore is taken by [`acquire`] and released when the returned [`Permit`]
+//! is dropped, including when the query fails.
+//!
+//! The slots are kept in shared memory and shared by all backends of the
+//! Postgres instance if [`init`] is called from the extension's `_PG_init()` and
+//! the extension is loaded with `shared_preload_libraries`, otherwise they are
+//! kept in each backend locally and only limit the backend itself.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use supabase_wrappers::semaphore;
+//!
+//! if let Some(_permit) = semaphore::acquire("my_server", 4, Some(Duration::from_secs(10))) {
+//!     // run the remote query, the slot is released when `_permit` is dropped
+//! }
+//! ```
+use heapless::FnvIndexMap;
+use pgrx::prelude::*;
+use pgrx::{pg_shmem_init, PGRXSharedMemory, PgLwLock, PgSharedMemoryInitialization};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// max length of semaphore name stored in shared memory, longer name is truncated
+const MAX_NAME_LEN: usize = 64;
+
+// max number of semaphores can be stored in shared memory, must be power of 2
+const MAX_SEMAPHORES: usize = 64;
+
+/// Max number of slots of a semaphore
+pub const MAX_SLOTS: usize = 64;
+
+// interval to check for a free slot while waiting
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+type SemaphoreName = heapless::String<MAX_NAME_LEN>;
+
+// pids of the backends holding the slots
+#[derive(Debug, Clone, Default)]
+struct Slots(heapless::Vec<i32, MAX_SLOTS>);
+
+unsafe impl PGRXSharedMemory for Slots {}
+
+impl Slots {
+    // take a slot if there are less than max slots taken, slots of exited
+    // backends are reclaimed first
+    fn try_take(&mut self, pid: i32, max: usize, shared: bool) -> bool {
+        if shared {
+            self.0
+                .retain(|&p| unsafe { !pg_sys::BackendPidGetProc(p).is_null() });
+        }
+        self.0.len() < max.min(MAX_SLOTS) && self.0.push(pid).is_ok()
+    }
+
+    fn release(&mut self, pid: i32) {
+        if let Some(idx) = self.0.iter().position(|&p| p == pid) {
+            self.0.swap_remove(idx);
+        }
+    }
+}
+
+static SHARED_SLOTS: PgLwLock<FnvIndexMap<SemaphoreName, Slots, MAX_SEMAPHORES>> = PgLwLock::new();
+
+// if shared memory is initialized, this is set in postmaster and inherited by
+// the backends
+static SHARED_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static LOCAL_SLOTS: Mutex<BTreeMap<String, Slots>> = Mutex::new(BTreeMap::new());
+
+/// Initialize shared memory for the semaphores
+///
+/// This must be called from `_PG_init()`, it only takes effect when the extension
+/// is loaded by `shared_preload_libraries`.
+pub fn init() {
+    if unsafe { !pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    pg_shmem_init!(SHARED_SLOTS);
+    SHARED_ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn semaphore_name(name: &str) -> SemaphoreName {
+    let mut sem_name = SemaphoreName::new();
+    for c in name.chars() {
+        if sem_name.push(c).is_err() {
+            break;
+        }
+    }
+    sem_name
+}
+
+fn current_pid() -> i32 {
+    unsafe { pg_sys::MyProcPid }
+}
+
+/// A taken slot of a semaphore, which is released when dropped
+#[derive(Debug)]
+pub struct Permit {
+    name: String,
+    pid: i32,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if SHARED_ENABLED.load(Ordering::Relaxed) {
+            let mut slots = SHARED_SLOTS.exclusive();
+            if let Some(slots) = slots.get_mut(&semaphore_name(&self.name)) {
+                slots.release(self.pid);
+            }
+        } else if let Ok(mut slots) = LOCAL_SLOTS.lock() {
+            if let Some(slots) = slots.get_mut(&self.name) {
+                slots.release(self.pid);
+            }
+        }
+    }
+}
+
+/// Try to take a slot of the named semaphore which has `max` slots, without
+/// waiting
+///
+/// Returns `None` if all the slots are taken, or if shared memory is used and
+/// it is full.
+pub fn try_acquire(name: &str, max: usize) -> Option<Permit> {
+    let pid = current_pid();
+    let taken = if SHARED_ENABLED.load(Ordering::Relaxed) {
+        let sem_name = semaphore_name(name);
+        let mut sems = SHARED_SLOTS.exclusive();
+        match sems.get_mut(&sem_name) {
+            Some(slots) => slots.try_take(pid, max, true),
+            None => {
+                let mut slots = Slots::default();
+                slots.try_take(pid, max, true) && sems.insert(sem_name, slots).is_ok()
+            }
+        }
+    } else {
+        let mut sems = LOCAL_SLOTS.lock().unwrap();
+        sems.entry(name.to_owned())
+            .or_default()
+            .try_take(pid, max, false)
+    };
+    taken.then(|| Permit {
+        name: name.to_owned(),
+        pid,
+    })
+}
+
+/// Take a slot of the named semaphore which has `max` slots, waiting for a
+/// free slot up to `timeout`, or indefinitely if it is `None`
+///
+/// Query cancellation is checked while waiting. Returns `None` if no slot is
+/// free before the timeout.
+pub fn acquire(name: &str, max: usize, timeout: Option<Duration>) -> Option<Permit> {
+    let start = Instant::now();
+    loop {
+        if let Some(permit) = try_acquire(name, max) {
+            return Some(permit);
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            return None;
+        }
+        thread::sleep(WAIT_INTERVAL);
+        unsafe {
+            if pg_sys::InterruptPending != 0 {
+                pg_sys::ProcessInterrupts();
+            }
+        }
+    }
+}
+
+/// Get the number of taken slots of the named semaphore
+pub fn in_use(name: &str) -> usize {
+    if SHARED_ENABLED.load(Ordering::Relaxed) {
+        let sems = SHARED_SLOTS.share();
+        sems.get(&semaphore_name(name))
+            .map_or(0, |slots| slots.0.len())
+    } else {
+        let sems = LOCAL_SLOTS.lock().unwrap();
+        sems.get(name).map_or(0, |slots| slots.0.len())
+    }
+}
@@ -2,6 +2,7 @@
 //!
 
 use crate::interface::{Cell, Column, Row};
+use crate::options::options_to_hashmap;
 use pgrx::pg_sys::panic::{ErrorReport, ErrorReportable};
 use pgrx::prelude::PgBuiltInOids;
 use pgrx::spi::Spi;
@@ -254,10 +255,13 @@ pub(super) unsafe fn extract_target_columns(
             }
 
             let type_oid = pg_sys::get_atttype((*rte).relid, attno);
+            let options = options_to_hashmap(pg_sys::GetForeignColumnOptions((*rte).relid, attno))
+                .report_unwrap();
             ret.push(Column {
                 name: CStr::from_ptr(attname).to_str().unwrap().to_owned(),
                 num: attno as usize,
                 type_oid,
+                options,
             });
         }
     }
@@ -265,6 +269,23 @@ pub(super) unsafe fn extract_target_columns(
     ret
 }
 
+// extract all the columns of a foreign table, except dropped and generated columns
+pub(super) unsafe fn extract_table_columns(rel: pg_sys::Relation) -> Vec<Column> {
+    let relid = (*rel).rd_id;
+    let tup_desc = PgTupleDesc::from_pg_unchecked((*rel).rd_att);
+    tup_desc
+        .iter()
+        .filter(|attr| !attr.attisdropped && attr.attgenerated == 0)
+        .map(|attr| Column {
+            name: pgrx::name_data_to_str(&attr.attname).to_owned(),
+            num: attr.attnum as usize,
+            type_oid: attr.atttypid,
+            options: options_to_hashmap(pg_sys::GetForeignColumnOptions(relid, attr.attnum))
+                .report_unwrap(),
+        })
+        .collect()
+}
+
 // trait for "serialize" and "deserialize" state from specified memory context,
 // so that it is safe to be carried between the planning and the execution
 pub(super) trait SerdeList {
@@ -22,3 +22,43 @@ COMMENT ON COLUMN wrappers_fdw_stats.bytes_in IS 'Total bytes input from origin'
 COMMENT ON COLUMN wrappers_fdw_stats.bytes_out IS 'Total bytes output to Postgres';
 COMMENT ON COLUMN wrappers_fdw_stats.metadata IS 'Metadata specific for the FDW';
 
+DROP TABLE IF EXISTS wrappers_connection_profiles;
+
+CREATE TABLE wrappers_connection_profiles (
+  name              text NOT NULL PRIMARY KEY,
+  options           jsonb NOT NULL DEFAULT '{}'::jsonb,
+  created_at        timestamptz NOT NULL DEFAULT timezone('utc'::text, now()),
+  updated_at        timestamptz NOT NULL DEFAULT timezone('utc'::text, now())
+);
+
+COMMENT ON TABLE wrappers_connection_profiles IS 'Wrappers named connection profiles';
+COMMENT ON COLUMN wrappers_connection_profiles.name IS 'Profile name referenced by the profile option of server or foreign table';
+COMMENT ON COLUMN wrappers_connection_profiles.options IS 'Connection options as a json object of strings, such as conn_string or conn_string_id';
+
+
+DROP TABLE IF EXISTS wrappers_watermarks;
+
+CREATE TABLE wrappers_watermarks (
+  name              text NOT NULL PRIMARY KEY,
+  value             text NOT NULL,
+  value_type        text NOT NULL,
+  created_at        timestamptz NOT NULL DEFAULT timezone('utc'::text, now()),
+  updated_at        timestamptz NOT NULL DEFAULT timezone('utc'::text, now())
+);
+
+COMMENT ON TABLE wrappers_watermarks IS 'Wrappers watermarks of incremental scans';
+COMMENT ON COLUMN wrappers_watermarks.name IS 'Watermark name set by the watermark_name option, or the source table name';
+COMMENT ON COLUMN wrappers_watermarks.value IS 'Max value of the watermark column read by the last complete scan, in text form';
+COMMENT ON COLUMN wrappers_watermarks.value_type IS 'Source type name of the watermark value';
+
+DROP TABLE IF EXISTS wrappers_cache_state;
+
+CREATE TABLE wrappers_cache_state (
+  cache_table       text NOT NULL PRIMARY KEY,
+  refreshed_at      timestamptz NOT NULL,
+  created_at        timestamptz NOT NULL DEFAULT timezone('utc'::text, now())
+);
+
+COMMENT ON TABLE wrappers_cache_state IS 'Wrappers refresh state of read-through cache tables';
+COMMENT ON COLUMN wrappers_cache_state.cache_table IS 'Cache table name set by the cache_table option';
+COMMENT ON COLUMN wrappers_cache_state.refreshed_at IS 'When the cache table was last refreshed from the source, in UTC';
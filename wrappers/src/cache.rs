@@ -0,0 +1,182 @@
+use pgrx::prelude::*;
+use pgrx::PgOid;
+use supabase_wrappers::prelude::Cell;
+
+// cache state table name
+const CACHE_STATE_TABLE: &str = "wrappers_cache_state";
+
+// get cache state table full qualified name
+fn get_cache_state_table() -> String {
+    let sql = format!(
+        "select b.nspname || '.{}'
+         from pg_catalog.pg_extension a join pg_namespace b on a.extnamespace = b.oid
+         where a.extname = 'wrappers'",
+        CACHE_STATE_TABLE
+    );
+    Spi::get_one(&sql)
+        .unwrap()
+        .unwrap_or_else(|| panic!("cannot find cache state table '{}'", CACHE_STATE_TABLE))
+}
+
+// get the quoted full qualified name of a cache table, return None if the
+// table doesn't exist
+#[allow(dead_code)]
+pub(crate) fn resolve_cache_table(name: &str) -> Option<String> {
+    Spi::get_one_with_args(
+        "select to_regclass($1)::text",
+        vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())],
+    )
+    .unwrap()
+}
+
+// get names and type oids of the cache table's columns in their defined order
+#[allow(dead_code)]
+pub(crate) fn get_cache_columns(cache_table: &str) -> Vec<(String, pg_sys::Oid)> {
+    Spi::connect(|client| {
+        client
+            .select(
+                "select attname::text, atttypid
+                 from pg_catalog.pg_attribute
+                 where attrelid = $1::regclass and attnum > 0 and not attisdropped
+                 order by attnum",
+                None,
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    cache_table.into_datum(),
+                )]),
+            )
+            .unwrap()
+            .filter_map(|row| {
+                let name = row.get::<String>(1).unwrap()?;
+                let type_oid = row.get::<pg_sys::Oid>(2).unwrap()?;
+                Some((name, type_oid))
+            })
+            .collect()
+    })
+}
+
+// check if the cache table was refreshed within the ttl
+#[allow(dead_code)]
+pub(crate) fn is_cache_fresh(cache_table: &str, ttl_secs: u64) -> bool {
+    let sql = format!(
+        "select refreshed_at > timezone('utc'::text, now()) - make_interval(secs => $2)
+         from {} where cache_table = $1",
+        get_cache_state_table()
+    );
+    Spi::get_one_with_args::<bool>(
+        &sql,
+        vec![
+            (PgBuiltInOids::TEXTOID.oid(), cache_table.into_datum()),
+            (
+                PgBuiltInOids::FLOAT8OID.oid(),
+                (ttl_secs as f64).into_datum(),
+            ),
+        ],
+    )
+    .unwrap()
+    .unwrap_or(false)
+}
+
+// replace the cache table's rows and mark it as refreshed, the cache is
+// refreshed in the current transaction so it is rolled back with the
+// transaction
+#[allow(dead_code)]
+pub(crate) fn refresh_cache(
+    cache_table: &str,
+    columns: &[(String, pg_sys::Oid)],
+    rows: impl Iterator<Item = Vec<Option<Cell>>>,
+) {
+    Spi::connect(|mut client| {
+        client
+            .update(&format!("delete from {}", cache_table), None, None)
+            .unwrap();
+
+        let sql = format!(
+            "insert into {} ({}) values ({})",
+            cache_table,
+            columns
+                .iter()
+                .map(|(name, _)| spi::quote_identifier(name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            (1..=columns.len())
+                .map(|i| format!("${}", i))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let stmt = client
+            .prepare(
+                &sql,
+                Some(columns.iter().map(|(_, oid)| PgOid::from(*oid)).collect()),
+            )
+            .unwrap();
+        for row in rows {
+            let args = row
+                .into_iter()
+                .map(|cell| cell.and_then(|cell| cell.into_datum()))
+                .collect();
+            client.update(&stmt, None, Some(args)).unwrap();
+        }
+
+        let sql = format!(
+            "insert into {} as c (cache_table, refreshed_at)
+             values($1, timezone('utc'::text, now()))
+             on conflict(cache_table)
+             do update set refreshed_at = excluded.refreshed_at",
+            get_cache_state_table()
+        );
+        client
+            .update(
+                &sql,
+                None,
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    cache_table.into_datum(),
+                )]),
+            )
+            .unwrap();
+    })
+}
+
+// read the columns of all rows in the cache table, return the cells of each
+// column and the number of rows
+#[allow(dead_code)]
+pub(crate) fn read_cache(cache_table: &str, columns: &[&str]) -> (Vec<Vec<Option<Cell>>>, usize) {
+    let sql = format!(
+        "select {} from {}",
+        columns
+            .iter()
+            .map(spi::quote_identifier)
+            .collect::<Vec<_>>()
+            .join(", "),
+        cache_table
+    );
+    Spi::connect(|client| {
+        let mut cells = vec![Vec::new(); columns.len()];
+        let mut row_cnt = 0;
+        for row in client.select(&sql, None, None).unwrap() {
+            for (i, col_cells) in cells.iter_mut().enumerate() {
+                col_cells.push(row.get::<Cell>(i + 1).unwrap());
+            }
+            row_cnt += 1;
+        }
+        (cells, row_cnt)
+    })
+}
+
+// mark the cache table as stale, so the next scan refreshes it
+#[allow(dead_code)]
+pub(crate) fn invalidate_cache(cache_table: &str) {
+    let sql = format!(
+        "delete from {} where cache_table = $1",
+        get_cache_state_table()
+    );
+    Spi::run_with_args(
+        &sql,
+        Some(vec![(
+            PgBuiltInOids::TEXTOID.oid(),
+            cache_table.into_datum(),
+        )]),
+    )
+    .unwrap();
+}
@@ -390,10 +390,26 @@ impl ForeignDataWrapper<BigQueryFdwError> for BigQueryFdw {
                         Cell::F32(v) => row_json[col_name] = json!(v),
                         Cell::F64(v) => row_json[col_name] = json!(v),
                         Cell::Numeric(v) => row_json[col_name] = json!(v),
-                        Cell::String(v) => row_json[col_name] = json!(v),
+                        Cell::String(v) | Cell::Enum(_, v) | Cell::Uuid(v) => {
+                            row_json[col_name] = json!(v)
+                        }
                         Cell::Date(v) => row_json[col_name] = json!(v),
                         Cell::Timestamp(v) => row_json[col_name] = json!(v),
+                        Cell::Timestamptz(v) => row_json[col_name] = json!(v),
                         Cell::Json(v) => row_json[col_name] = json!(v),
+                        Cell::Bytea(_) => {
+                            return Err(BigQueryFdwError::UnsupportedFieldType(FieldType::Bytes))
+                        }
+                        Cell::BoolArray(v) => row_json[col_name] = json!(v),
+                        Cell::I16Array(v) => row_json[col_name] = json!(v),
+                        Cell::I32Array(v) => row_json[col_name] = json!(v),
+                        Cell::I64Array(v) => row_json[col_name] = json!(v),
+                        Cell::F32Array(v) => row_json[col_name] = json!(v),
+                        Cell::F64Array(v) => row_json[col_name] = json!(v),
+                        Cell::StringArray(v) => row_json[col_name] = json!(v),
+                        Cell::Composite(..) | Cell::MdArray(..) | Cell::Range(..) => {
+                            return Err(BigQueryFdwError::UnsupportedFieldType(FieldType::Struct))
+                        }
                     }
                 }
             }
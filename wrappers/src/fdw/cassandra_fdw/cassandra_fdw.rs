@@ -0,0 +1,406 @@
+use crate::stats;
+use futures::StreamExt;
+use pgrx::pg_sys;
+use pgrx::prelude::{AnyNumeric, Timestamp, TimestampWithTimeZone};
+use scylla::frame::response::result::CqlValue;
+use scylla::query::Query;
+use scylla::transport::iterator::RowIterator;
+use scylla::{Session, SessionBuilder};
+use std::collections::HashMap;
+
+use supabase_wrappers::metrics::{self, ErrorClass, Metric};
+use supabase_wrappers::prelude::*;
+
+use super::{CassandraFdwError, CassandraFdwResult};
+
+// microseconds between unix epoch (1970-01-01) and Postgres epoch (2000-01-01)
+const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+// a column of the Cassandra table, fetched from system_schema.columns
+struct CqlColumn {
+    name: String,
+    // 'partition_key', 'clustering' or 'regular'
+    kind: String,
+    position: i32,
+    cql_type: String,
+}
+
+// quote a CQL identifier, so it is case sensitive
+fn quote_identifier(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36 && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
+
+// convert a qual value to CQL literal of the column type, return None if it
+// cannot be converted exactly so the qual won't be pushed down
+fn cql_literal(cell: &Cell, cql_type: &str) -> Option<String> {
+    match (cql_type, cell) {
+        ("text" | "ascii" | "varchar", Cell::String(s)) => {
+            Some(format!("'{}'", s.replace('\'', "''")))
+        }
+        ("uuid" | "timeuuid", Cell::String(s) | Cell::Uuid(s)) if is_uuid(s) => Some(s.clone()),
+        ("boolean", Cell::Bool(v)) => Some(v.to_string()),
+        ("tinyint" | "smallint" | "int" | "bigint" | "varint", Cell::I8(v)) => Some(v.to_string()),
+        ("tinyint" | "smallint" | "int" | "bigint" | "varint", Cell::I16(v)) => Some(v.to_string()),
+        ("int" | "bigint" | "varint", Cell::I32(v)) => Some(v.to_string()),
+        ("bigint" | "varint", Cell::I64(v)) => Some(v.to_string()),
+        ("decimal", Cell::Numeric(v)) => {
+            let s = v.to_string();
+            s.chars()
+                .all(|c| c.is_ascii_digit() || c == '.' || c == '-')
+                .then_some(s)
+        }
+        ("double", Cell::F64(v)) if v.is_finite() => Some(v.to_string()),
+        ("float", Cell::F32(v)) if v.is_finite() => Some(v.to_string()),
+        // timestamp literal is milliseconds since unix epoch
+        ("timestamp", Cell::Timestamp(v)) => micros_to_millis(v.into_inner()),
+        ("timestamp", Cell::Timestamptz(v)) => micros_to_millis(v.into_inner()),
+        _ => None,
+    }
+}
+
+// convert Postgres microseconds to unix epoch milliseconds, if it is exact
+fn micros_to_millis(pg_micros: i64) -> Option<String> {
+    let micros = pg_micros.checked_add(PG_EPOCH_MICROS)?;
+    (micros % 1000 == 0).then(|| (micros / 1000).to_string())
+}
+
+// convert a CQL value to cell which matches the target column type
+fn cql_to_cell(value: CqlValue, tgt_col: &Column) -> CassandraFdwResult<Cell> {
+    let cell = match (tgt_col.type_oid, value) {
+        (pg_sys::BOOLOID, CqlValue::Boolean(v)) => Cell::Bool(v),
+        (pg_sys::INT2OID, CqlValue::SmallInt(v)) => Cell::I16(v),
+        (pg_sys::INT2OID, CqlValue::TinyInt(v)) => Cell::I16(v as i16),
+        (pg_sys::INT4OID, CqlValue::Int(v)) => Cell::I32(v),
+        (pg_sys::INT4OID, CqlValue::SmallInt(v)) => Cell::I32(v as i32),
+        (pg_sys::INT4OID, CqlValue::TinyInt(v)) => Cell::I32(v as i32),
+        (pg_sys::INT8OID, CqlValue::BigInt(v)) => Cell::I64(v),
+        (pg_sys::INT8OID, CqlValue::Counter(v)) => Cell::I64(v.0),
+        (pg_sys::INT8OID, CqlValue::Int(v)) => Cell::I64(v as i64),
+        (pg_sys::FLOAT4OID, CqlValue::Float(v)) => Cell::F32(v),
+        (pg_sys::FLOAT8OID, CqlValue::Double(v)) => Cell::F64(v),
+        (pg_sys::FLOAT8OID, CqlValue::Float(v)) => Cell::F64(v as f64),
+        (pg_sys::NUMERICOID, CqlValue::Decimal(v)) => AnyNumeric::try_from(v.to_string().as_str())
+            .map(Cell::Numeric)
+            .map_err(|err| CassandraFdwError::NumericConversionError(err.to_string()))?,
+        (pg_sys::NUMERICOID, CqlValue::Varint(v)) => AnyNumeric::try_from(v.to_string().as_str())
+            .map(Cell::Numeric)
+            .map_err(|err| CassandraFdwError::NumericConversionError(err.to_string()))?,
+        (pg_sys::NUMERICOID, CqlValue::Int(v)) => Cell::Numeric(AnyNumeric::from(v)),
+        (pg_sys::NUMERICOID, CqlValue::BigInt(v)) => Cell::Numeric(AnyNumeric::from(v)),
+        (pg_sys::TEXTOID | pg_sys::VARCHAROID, CqlValue::Text(v) | CqlValue::Ascii(v)) => {
+            Cell::String(v)
+        }
+        (pg_sys::TEXTOID | pg_sys::VARCHAROID, CqlValue::Uuid(v) | CqlValue::Timeuuid(v)) => {
+            Cell::String(v.to_string())
+        }
+        (pg_sys::UUIDOID, CqlValue::Uuid(v) | CqlValue::Timeuuid(v)) => Cell::Uuid(v.to_string()),
+        (pg_sys::TEXTOID | pg_sys::VARCHAROID, CqlValue::Inet(v)) => Cell::String(v.to_string()),
+        (pg_sys::TIMESTAMPOID, CqlValue::Timestamp(v)) => Cell::Timestamp(Timestamp::from(
+            unix_millis_to_pg_micros(v.num_milliseconds())?,
+        )),
+        (pg_sys::TIMESTAMPTZOID, CqlValue::Timestamp(v)) => {
+            let micros = unix_millis_to_pg_micros(v.num_milliseconds())?;
+            TimestampWithTimeZone::try_from(micros)
+                .map(Cell::Timestamptz)
+                .map_err(|err| CassandraFdwError::DatetimeConversionError(err.to_string()))?
+        }
+        (
+            pg_sys::BOOLOID
+            | pg_sys::INT2OID
+            | pg_sys::INT4OID
+            | pg_sys::INT8OID
+            | pg_sys::FLOAT4OID
+            | pg_sys::FLOAT8OID
+            | pg_sys::NUMERICOID
+            | pg_sys::TEXTOID
+            | pg_sys::VARCHAROID
+            | pg_sys::UUIDOID
+            | pg_sys::TIMESTAMPOID
+            | pg_sys::TIMESTAMPTZOID,
+            _,
+        ) => return Err(CassandraFdwError::ColumnTypeNotMatch(tgt_col.name.clone())),
+        _ => {
+            return Err(CassandraFdwError::UnsupportedColumnType(
+                tgt_col.name.clone(),
+            ))
+        }
+    };
+    Ok(cell)
+}
+
+fn unix_millis_to_pg_micros(millis: i64) -> CassandraFdwResult<i64> {
+    millis
+        .checked_mul(1000)
+        .and_then(|micros| micros.checked_sub(PG_EPOCH_MICROS))
+        .ok_or_else(|| CassandraFdwError::DatetimeConversionError(millis.to_string()))
+}
+
+#[wrappers_fdw(
+    version = "0.1.0",
+    author = "Supabase",
+    website = "https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/cassandra_fdw",
+    error_type = "CassandraFdwError"
+)]
+pub(crate) struct CassandraFdw {
+    rt: Runtime,
+    contact_points: Vec<String>,
+    username: Option<String>,
+    password: Option<String>,
+    session: Option<Session>,
+    tgt_cols: Vec<Column>,
+    sql: String,
+    rows: Option<RowIterator>,
+    row_cnt: i64,
+}
+
+impl CassandraFdw {
+    const FDW_NAME: &str = "CassandraFdw";
+
+    fn create_session(&mut self) -> CassandraFdwResult<()> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+        let mut builder = SessionBuilder::new().known_nodes(&self.contact_points);
+        if let Some(username) = &self.username {
+            builder = builder.user(username, self.password.clone().unwrap_or_default());
+        }
+        self.session = Some(self.rt.block_on(builder.build())?);
+        metrics::inc(Self::FDW_NAME, Metric::ConnectionsOpened, 1);
+        Ok(())
+    }
+
+    // get columns of the table, ordered by key position
+    fn get_table_columns(&self, keyspace: &str, table: &str) -> CassandraFdwResult<Vec<CqlColumn>> {
+        let mut columns = Vec::new();
+        if let Some(ref session) = self.session {
+            let result = self.rt.block_on(session.query(
+                "select column_name, kind, position, type from system_schema.columns \
+                 where keyspace_name = ? and table_name = ?",
+                (keyspace, table),
+            ))?;
+            for row in result.rows_typed::<(String, String, i32, String)>()? {
+                let (name, kind, position, cql_type) =
+                    row.map_err(|err| CassandraFdwError::InvalidSchema(err.to_string()))?;
+                columns.push(CqlColumn {
+                    name,
+                    kind,
+                    position,
+                    cql_type,
+                });
+            }
+        }
+        if columns.is_empty() {
+            return Err(CassandraFdwError::TableNotFound(
+                keyspace.to_string(),
+                table.to_string(),
+            ));
+        }
+        columns.sort_by_key(|c| c.position);
+        Ok(columns)
+    }
+
+    // CQL only allows conditions on the primary key, so pushdown is limited to
+    // equality on all the partition key columns, optionally followed by
+    // equality on a prefix of the clustering columns. Other quals are left to
+    // Postgres. Return the pushed down conditions and whether all quals are
+    // pushed down.
+    fn pushdown_quals(quals: &[Qual], table_columns: &[CqlColumn]) -> (Vec<String>, bool) {
+        let eq_cond = |col: &CqlColumn| {
+            quals.iter().find_map(|qual| {
+                if qual.field != col.name || qual.operator != "=" || qual.use_or {
+                    return None;
+                }
+                match &qual.value {
+                    Value::Cell(cell) => cql_literal(cell, &col.cql_type)
+                        .map(|lit| (qual, format!("{} = {}", quote_identifier(&col.name), lit))),
+                    Value::Array(_) => None,
+                }
+            })
+        };
+
+        let mut pushed = Vec::new();
+        let partition_keys = table_columns
+            .iter()
+            .filter(|c| c.kind == "partition_key")
+            .map(eq_cond)
+            .collect::<Option<Vec<_>>>();
+        if let Some(partition_keys) = partition_keys {
+            pushed.extend(partition_keys);
+            pushed.extend(
+                table_columns
+                    .iter()
+                    .filter(|c| c.kind == "clustering")
+                    .map_while(eq_cond),
+            );
+        }
+
+        let all_pushed = quals
+            .iter()
+            .all(|qual| pushed.iter().any(|(q, _)| std::ptr::eq(*q, qual)));
+        let conds = pushed.into_iter().map(|(_, cond)| cond).collect();
+        (conds, all_pushed)
+    }
+
+    fn query(&mut self) -> CassandraFdwResult<()> {
+        if let Some(ref session) = self.session {
+            // rows are fetched page by page when iterating
+            let rows = self
+                .rt
+                .block_on(session.query_iter(Query::new(self.sql.clone()), ()))?;
+            self.rows = Some(rows);
+        }
+        Ok(())
+    }
+
+    fn deparse(
+        &self,
+        quals: &[Qual],
+        columns: &[Column],
+        sorts: &[Sort],
+        limit: &Option<Limit>,
+        table: &str,
+        table_columns: &[CqlColumn],
+    ) -> String {
+        // CQL requires at least one selector, so select the first key column
+        // when no column is needed, for example, in 'count(*)'
+        let tgts = if columns.is_empty() {
+            quote_identifier(&table_columns[0].name)
+        } else {
+            columns
+                .iter()
+                .map(|c| quote_identifier(&c.name))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        let mut sql = format!("select {} from {}", tgts, table);
+
+        let (conds, all_pushed) = Self::pushdown_quals(quals, table_columns);
+        if !conds.is_empty() {
+            sql.push_str(&format!(" where {}", conds.join(" and ")));
+        }
+
+        // CQL can only sort by clustering columns within a partition, so sorts
+        // are done by Postgres and limit can only be pushed down when there is
+        // no sort and no local filter
+        if let Some(limit) = limit {
+            if sorts.is_empty() && all_pushed {
+                sql.push_str(&format!(" limit {}", limit.count + limit.offset));
+            }
+        }
+
+        sql
+    }
+}
+
+impl ForeignDataWrapper<CassandraFdwError> for CassandraFdw {
+    fn error_class(err: &CassandraFdwError) -> ErrorClass {
+        err.class()
+    }
+
+    fn new(options: &HashMap<String, String>) -> CassandraFdwResult<Self> {
+        let contact_points = require_option("contact_points", options)?
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect();
+        let password = match options.get("password") {
+            Some(password) => Some(password.to_owned()),
+            None => options
+                .get("password_id")
+                .and_then(|password_id| get_vault_secret(password_id)),
+        };
+
+        stats::inc_stats(Self::FDW_NAME, stats::Metric::CreateTimes, 1);
+
+        Ok(CassandraFdw {
+            rt: create_async_runtime()?,
+            contact_points,
+            username: options.get("username").cloned(),
+            password,
+            session: None,
+            tgt_cols: Vec::new(),
+            sql: String::new(),
+            rows: None,
+            row_cnt: 0,
+        })
+    }
+
+    fn begin_scan(
+        &mut self,
+        quals: &[Qual],
+        columns: &[Column],
+        sorts: &[Sort],
+        limit: &Option<Limit>,
+        options: &HashMap<String, String>,
+    ) -> CassandraFdwResult<()> {
+        let keyspace = require_option("keyspace", options)?;
+        let table = require_option("table", options)?;
+
+        self.create_session()?;
+
+        let table_columns = self.get_table_columns(keyspace, table)?;
+        let sql = self.deparse(
+            quals,
+            columns,
+            sorts,
+            limit,
+            &format!("{}.{}", quote_identifier(keyspace), quote_identifier(table)),
+            &table_columns,
+        );
+
+        self.tgt_cols = columns.to_vec();
+        self.sql = sql;
+        self.row_cnt = 0;
+
+        self.query()
+    }
+
+    fn iter_scan(&mut self, row: &mut Row) -> CassandraFdwResult<Option<()>> {
+        if let Some(ref mut rows) = self.rows {
+            if let Some(src_row) = self.rt.block_on(rows.next()) {
+                let src_row = src_row?;
+                for (tgt_col, value) in self.tgt_cols.iter().zip(src_row.columns) {
+                    let cell = value.map(|v| cql_to_cell(v, tgt_col)).transpose()?;
+                    row.push(&tgt_col.name, cell);
+                }
+                self.row_cnt += 1;
+                return Ok(Some(()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn re_scan(&mut self) -> CassandraFdwResult<()> {
+        self.query()
+    }
+
+    fn end_scan(&mut self) -> CassandraFdwResult<()> {
+        self.rows.take();
+        if self.row_cnt > 0 {
+            stats::inc_stats(Self::FDW_NAME, stats::Metric::RowsIn, self.row_cnt);
+            stats::inc_stats(Self::FDW_NAME, stats::Metric::RowsOut, self.row_cnt);
+        }
+        Ok(())
+    }
+
+    fn validator(
+        options: Vec<Option<String>>,
+        catalog: Option<pg_sys::Oid>,
+    ) -> CassandraFdwResult<()> {
+        if let Some(oid) = catalog {
+            if oid == FOREIGN_SERVER_RELATION_ID {
+                check_options_contain(&options, "contact_points")?;
+            } else if oid == FOREIGN_TABLE_RELATION_ID {
+                check_options_contain(&options, "keyspace")?;
+                check_options_contain(&options, "table")?;
+            }
+        }
+
+        Ok(())
+    }
+}
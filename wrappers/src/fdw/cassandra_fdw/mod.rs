@@ -0,0 +1,72 @@
+#![allow(clippy::module_inception)]
+mod cassandra_fdw;
+mod tests;
+
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::PgSqlErrorCode;
+use scylla::transport::errors::{NewSessionError, QueryError};
+use scylla::transport::query_result::RowsExpectedError;
+use thiserror::Error;
+
+use supabase_wrappers::metrics::ErrorClass;
+use supabase_wrappers::prelude::{CreateRuntimeError, OptionsError};
+
+#[derive(Error, Debug)]
+enum CassandraFdwError {
+    #[error("table '{0}.{1}' not found")]
+    TableNotFound(String, String),
+
+    #[error("column '{0}' data type is not supported")]
+    UnsupportedColumnType(String),
+
+    #[error("column '{0}' data type not match")]
+    ColumnTypeNotMatch(String),
+
+    #[error("numeric conversion error: {0}")]
+    NumericConversionError(String),
+
+    #[error("datetime conversion error: {0}")]
+    DatetimeConversionError(String),
+
+    #[error("invalid schema response: {0}")]
+    InvalidSchema(String),
+
+    #[error("{0}")]
+    OptionsError(#[from] OptionsError),
+
+    #[error("{0}")]
+    CreateRuntimeError(#[from] CreateRuntimeError),
+
+    #[error("connect to Cassandra failed: {0}")]
+    NewSessionError(#[from] NewSessionError),
+
+    #[error("query failed: {0}")]
+    QueryError(#[from] QueryError),
+
+    #[error("{0}")]
+    RowsExpectedError(#[from] RowsExpectedError),
+}
+
+impl CassandraFdwError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::NewSessionError(_) => ErrorClass::Connection,
+            Self::QueryError(_) | Self::TableNotFound(..) => ErrorClass::Query,
+            Self::UnsupportedColumnType(_)
+            | Self::ColumnTypeNotMatch(_)
+            | Self::NumericConversionError(_)
+            | Self::DatetimeConversionError(_)
+            | Self::InvalidSchema(_)
+            | Self::RowsExpectedError(_) => ErrorClass::Data,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+impl From<CassandraFdwError> for ErrorReport {
+    fn from(value: CassandraFdwError) -> Self {
+        ErrorReport::new(PgSqlErrorCode::ERRCODE_FDW_ERROR, format!("{value}"), "")
+    }
+}
+
+type CassandraFdwResult<T> = Result<T, CassandraFdwError>;
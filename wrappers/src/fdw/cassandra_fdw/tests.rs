@@ -0,0 +1,259 @@
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::prelude::*;
+    use pgrx::spi::SpiClient;
+    use scylla::SessionBuilder;
+    use supabase_wrappers::prelude::create_async_runtime;
+
+    const CONTACT_POINTS: &str = "localhost:9042";
+
+    // run statements on Cassandra
+    fn cassandra_exec(stmts: &[&str]) {
+        let rt = create_async_runtime().expect("failed to create runtime");
+        rt.block_on(async {
+            let session = SessionBuilder::new()
+                .known_node(CONTACT_POINTS)
+                .build()
+                .await
+                .expect("connect to Cassandra");
+            for stmt in stmts {
+                session.query(*stmt, ()).await?;
+            }
+            Ok::<_, scylla::transport::errors::QueryError>(())
+        })
+        .expect("execute statements in Cassandra");
+    }
+
+    fn create_server(c: &mut SpiClient<'_>) {
+        c.update(
+            r#"CREATE FOREIGN DATA WRAPPER cassandra_wrapper
+                     HANDLER cassandra_fdw_handler VALIDATOR cassandra_fdw_validator"#,
+            None,
+            None,
+        )
+        .unwrap();
+        c.update(
+            &format!(
+                r#"CREATE SERVER my_cassandra_server
+                     FOREIGN DATA WRAPPER cassandra_wrapper
+                     OPTIONS (
+                       contact_points '{}'
+                     )"#,
+                CONTACT_POINTS
+            ),
+            None,
+            None,
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn cassandra_smoketest() {
+        Spi::connect(|mut c| {
+            cassandra_exec(&[
+                "CREATE KEYSPACE IF NOT EXISTS supa
+                 WITH replication = {'class': 'SimpleStrategy', 'replication_factor': 1}",
+                "DROP TABLE IF EXISTS supa.users",
+                "CREATE TABLE supa.users (
+                    id uuid PRIMARY KEY, name text, age int, visits bigint,
+                    created_at timestamp, balance decimal
+                )",
+                "INSERT INTO supa.users (id, name, age, visits, created_at, balance)
+                 VALUES (8d5f2c3e-6b1a-4c4e-9a7d-2f0e1b3c4d5e, 'foo', 42, 1234567890123,
+                         '2023-07-01 12:34:56+0000', 12.345)",
+                "INSERT INTO supa.users (id, name) VALUES (1c7a3e2d-0f4b-4d8e-8c6a-5b9e7f1a2d3c, 'bar')",
+            ]);
+
+            create_server(&mut c);
+            c.update(
+                r#"
+                  CREATE FOREIGN TABLE users (
+                    id text,
+                    name text,
+                    age integer,
+                    visits bigint,
+                    created_at timestamp,
+                    balance numeric
+                  )
+                  SERVER my_cassandra_server
+                  OPTIONS (
+                    keyspace 'supa',
+                    table 'users'
+                  )
+             "#,
+                None,
+                None,
+            )
+            .unwrap();
+
+            let results = c
+                .select(
+                    "SELECT name, age, visits, created_at::text, balance::text FROM users
+                     WHERE id = '8d5f2c3e-6b1a-4c4e-9a7d-2f0e1b3c4d5e'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| {
+                    (
+                        r.get::<&str>(1).unwrap().unwrap().to_owned(),
+                        r.get::<i32>(2).unwrap(),
+                        r.get::<i64>(3).unwrap(),
+                        r.get::<&str>(4).unwrap().map(|s| s.to_owned()),
+                        r.get::<&str>(5).unwrap().map(|s| s.to_owned()),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                results,
+                vec![(
+                    "foo".to_owned(),
+                    Some(42),
+                    Some(1234567890123),
+                    Some("2023-07-01 12:34:56".to_owned()),
+                    Some("12.345".to_owned()),
+                )]
+            );
+
+            // missing values are read as null
+            let results = c
+                .select("SELECT name, age FROM users ORDER BY name", None, None)
+                .unwrap()
+                .map(|r| {
+                    (
+                        r.get_by_name::<&str, _>("name").unwrap().unwrap(),
+                        r.get_by_name::<i32, _>("age").unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(results, vec![("bar", None), ("foo", Some(42))]);
+
+            // uuid can be read as uuid as well as text
+            c.update(
+                r#"
+                  CREATE FOREIGN TABLE users_by_uuid (
+                    id uuid,
+                    name text
+                  )
+                  SERVER my_cassandra_server
+                  OPTIONS (
+                    keyspace 'supa',
+                    table 'users'
+                  )
+             "#,
+                None,
+                None,
+            )
+            .unwrap();
+            let results = c
+                .select(
+                    "SELECT id::text, name FROM users_by_uuid
+                     WHERE id = '1c7a3e2d-0f4b-4d8e-8c6a-5b9e7f1a2d3c'::uuid",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .map(|r| {
+                    (
+                        r.get::<&str>(1).unwrap().unwrap(),
+                        r.get::<&str>(2).unwrap().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                results,
+                vec![("1c7a3e2d-0f4b-4d8e-8c6a-5b9e7f1a2d3c", "bar")]
+            );
+        });
+    }
+
+    #[pg_test]
+    fn cassandra_key_pushdown() {
+        Spi::connect(|mut c| {
+            cassandra_exec(&[
+                "CREATE KEYSPACE IF NOT EXISTS supa
+                 WITH replication = {'class': 'SimpleStrategy', 'replication_factor': 1}",
+                "DROP TABLE IF EXISTS supa.events",
+                "CREATE TABLE supa.events (
+                    tenant text, day int, seq int, msg text,
+                    PRIMARY KEY ((tenant), day, seq)
+                )",
+                "INSERT INTO supa.events (tenant, day, seq, msg) VALUES ('a', 1, 1, 'a11')",
+                "INSERT INTO supa.events (tenant, day, seq, msg) VALUES ('a', 1, 2, 'a12')",
+                "INSERT INTO supa.events (tenant, day, seq, msg) VALUES ('a', 2, 1, 'a21')",
+                "INSERT INTO supa.events (tenant, day, seq, msg) VALUES ('b', 1, 1, 'b11')",
+            ]);
+
+            create_server(&mut c);
+            c.update(
+                r#"
+                  CREATE FOREIGN TABLE events (
+                    tenant text,
+                    day integer,
+                    seq integer,
+                    msg text
+                  )
+                  SERVER my_cassandra_server
+                  OPTIONS (
+                    keyspace 'supa',
+                    table 'events'
+                  )
+             "#,
+                None,
+                None,
+            )
+            .unwrap();
+
+            let query = |c: &SpiClient<'_>, sql: &str| {
+                c.select(sql, None, None)
+                    .unwrap()
+                    .map(|r| r.get_by_name::<&str, _>("msg").unwrap().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            };
+
+            // partition key and clustering key prefix are pushed down
+            assert_eq!(
+                query(
+                    &c,
+                    "SELECT msg FROM events WHERE tenant = 'a' AND day = 1 ORDER BY seq"
+                ),
+                vec!["a11", "a12"]
+            );
+
+            // clustering key without partition key is filtered locally
+            assert_eq!(
+                query(&c, "SELECT msg FROM events WHERE seq = 1 ORDER BY msg"),
+                vec!["a11", "a21", "b11"]
+            );
+
+            // range condition on clustering key is filtered locally
+            assert_eq!(
+                query(
+                    &c,
+                    "SELECT msg FROM events WHERE tenant = 'a' AND day > 1 ORDER BY msg"
+                ),
+                vec!["a21"]
+            );
+
+            // limit is not pushed down when some conditions are filtered locally
+            assert_eq!(
+                query(
+                    &c,
+                    "SELECT msg FROM events WHERE tenant = 'a' AND msg LIKE '%2%' LIMIT 1"
+                )
+                .len(),
+                1
+            );
+
+            // count without any target columns
+            let cnt = c
+                .select("SELECT count(*) FROM events", None, None)
+                .unwrap()
+                .first()
+                .get_one::<i64>()
+                .unwrap();
+            assert_eq!(cnt, Some(4));
+        });
+    }
+}
@@ -1,74 +1,1477 @@
+use crate::cache;
+use crate::profile;
 use crate::stats;
-use chrono::{Date, DateTime, NaiveDate, NaiveDateTime, Utc};
+use crate::watermark;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Date, DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use chrono_tz::Tz;
-use clickhouse_rs::{types, types::Block, types::SqlType, ClientHandle, Pool};
-use pgrx::to_timestamp;
+use clickhouse_rs::{
+    types, types::Block, types::Options, types::Query, types::SqlType, ClientHandle, Pool,
+};
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures::StreamExt;
+use pgrx::heap_tuple::PgHeapTuple;
+use pgrx::pg_sys::panic::{ErrorReport, ErrorReportable};
+use pgrx::{
+    fcinfo, iter::TableIterator, name, pg_extern, pg_guard, pg_sys, spi::Spi, to_timestamp,
+    AllocatedByRust, AnyNumeric, FromDatum, IntoDatum, JsonB, PgBuiltInOids, PgSqlErrorCode,
+    PgTupleDesc, Timestamp, TimestampWithTimeZone,
+};
 use regex::{Captures, Regex};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use supabase_wrappers::auth;
+use supabase_wrappers::metrics::{self, ErrorClass, Metric};
 use supabase_wrappers::prelude::*;
+use supabase_wrappers::semaphore::{self, Permit};
+use supabase_wrappers::FdwRoutine;
 
 use super::{ClickHouseFdwError, ClickHouseFdwResult};
 
-fn field_to_cell(row: &types::Row<types::Complex>, i: usize) -> ClickHouseFdwResult<Option<Cell>> {
-    let sql_type = row.sql_type(i)?;
+// read all values of a block column, the type dispatch is done once for the whole
+// column rather than for each cell, value is None if the column is Nullable and
+// the value is null
+macro_rules! read_column {
+    ($block:ident, $col:ident, $nullable:expr, $t:ty, $f:expr) => {
+        (0..$block.row_count())
+            .map(|row| {
+                let value = if $nullable {
+                    $block.get::<Option<$t>, usize>(row, $col)?
+                } else {
+                    Some($block.get::<$t, usize>(row, $col)?)
+                };
+                value.map($f).transpose()
+            })
+            .collect::<Vec<ClickHouseFdwResult<Option<Cell>>>>()
+    };
+}
+
+// convert a block column to cells
+fn column_to_cells(block: &Block, col: usize) -> Vec<ClickHouseFdwResult<Option<Cell>>> {
+    let (sql_type, nullable) = match block.columns()[col].sql_type() {
+        SqlType::Nullable(inner) => (inner.clone(), true),
+        sql_type => (sql_type, false),
+    };
     match sql_type {
-        SqlType::UInt8 => {
-            // Bool is stored as UInt8 in ClickHouse, so we treat it as bool here
-            let value = row.get::<u8, usize>(i)?;
-            Ok(Some(Cell::Bool(value != 0)))
-        }
-        SqlType::Int16 => {
-            let value = row.get::<i16, usize>(i)?;
-            Ok(Some(Cell::I16(value)))
+        // Bool is stored as UInt8 in ClickHouse, so we treat it as bool here
+        SqlType::UInt8 => read_column!(block, col, nullable, u8, |v| Ok(Cell::Bool(v != 0))),
+        SqlType::Int8 => read_column!(block, col, nullable, i8, |v| Ok(Cell::I16(v as i16))),
+        SqlType::Int16 => read_column!(block, col, nullable, i16, |v| Ok(Cell::I16(v))),
+        SqlType::UInt16 => read_column!(block, col, nullable, u16, |v| Ok(Cell::I32(v as i32))),
+        SqlType::Int32 => read_column!(block, col, nullable, i32, |v| Ok(Cell::I32(v))),
+        SqlType::UInt32 => read_column!(block, col, nullable, u32, |v| Ok(Cell::I64(v as i64))),
+        SqlType::Float32 => read_column!(block, col, nullable, f32, |v| Ok(Cell::F32(v))),
+        SqlType::Float64 => read_column!(block, col, nullable, f64, |v| Ok(Cell::F64(v))),
+        // UInt64 values beyond bigint are read as numeric to keep their precision
+        SqlType::UInt64 => read_column!(block, col, nullable, u64, |v| {
+            Ok(match i64::try_from(v) {
+                Ok(v) => Cell::I64(v),
+                Err(_) => Cell::Numeric(AnyNumeric::from(v)),
+            })
+        }),
+        SqlType::Int64 => read_column!(block, col, nullable, i64, |v| Ok(Cell::I64(v))),
+        SqlType::String | SqlType::FixedString(_) => {
+            read_column!(block, col, nullable, String, |v| Ok(Cell::String(v)))
         }
-        SqlType::UInt16 => {
-            let value = row.get::<u16, usize>(i)?;
-            Ok(Some(Cell::I32(value as i32)))
+        // enums are read as their names
+        SqlType::Enum8(values) => read_column!(block, col, nullable, types::Enum8, |v| {
+            Ok(Cell::String(enum_name(&values, v.internal())))
+        }),
+        SqlType::Enum16(values) => read_column!(block, col, nullable, types::Enum16, |v| {
+            Ok(Cell::String(enum_name(&values, v.internal())))
+        }),
+        SqlType::Ipv4 => read_column!(block, col, nullable, Ipv4Addr, |v| {
+            Ok(Cell::String(v.to_string()))
+        }),
+        SqlType::Ipv6 => read_column!(block, col, nullable, Ipv6Addr, |v| {
+            Ok(Cell::String(v.to_string()))
+        }),
+        SqlType::Decimal(_, _) => {
+            read_column!(block, col, nullable, types::Decimal, |v| {
+                AnyNumeric::try_from(v.to_string().as_str())
+                    .map(Cell::Numeric)
+                    .map_err(|err| ClickHouseFdwError::NumericConversionError(err.to_string()))
+            })
         }
-        SqlType::Int32 => {
-            let value = row.get::<i32, usize>(i)?;
-            Ok(Some(Cell::I32(value)))
+        SqlType::Date => read_column!(block, col, nullable, Date<_>, |v| {
+            let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+            let seconds_from_epoch = v.naive_utc().signed_duration_since(epoch).num_seconds();
+            let ts = to_timestamp(seconds_from_epoch as f64);
+            Ok(Cell::Date(pgrx::Date::from(ts)))
+        }),
+        // DateTime64 keeps its sub-second part up to microseconds
+        SqlType::DateTime(_) => read_column!(block, col, nullable, DateTime<_>, |v| {
+            let ts = to_timestamp(v.timestamp_micros() as f64 / 1_000_000.0);
+            Ok(Cell::Timestamp(ts.to_utc()))
+        }),
+        SqlType::Array(elem_type) => array_column_to_cells(block, col, elem_type),
+        // Nothing is the type of a NULL constant, such as `NULL as c` in an
+        // aggregate query, it has no values so it is read as null
+        SqlType::Nothing => (0..block.row_count()).map(|_| Ok(None)).collect(),
+        _ => unsupported_column(block, &sql_type.to_string()),
+    }
+}
+
+// get the name of an enum value, the value itself is used if it has no name
+fn enum_name<T: PartialEq + ToString>(values: &[(String, T)], value: T) -> String {
+    values
+        .iter()
+        .find(|(_, v)| *v == value)
+        .map(|(name, _)| name.clone())
+        .unwrap_or_else(|| value.to_string())
+}
+
+// convert an Array column to array cells, ClickHouse array cannot be Nullable and
+// its elements are not Nullable either
+fn array_column_to_cells(
+    block: &Block,
+    col: usize,
+    elem_type: &SqlType,
+) -> Vec<ClickHouseFdwResult<Option<Cell>>> {
+    fn to_cells<T, U>(value: Vec<T>, f: impl Fn(T) -> U) -> Vec<Option<U>> {
+        value.into_iter().map(|v| Some(f(v))).collect()
+    }
+    match elem_type {
+        // Bool is stored as UInt8 in ClickHouse, so we treat it as bool here
+        SqlType::UInt8 => read_column!(block, col, false, Vec<u8>, |v| {
+            Ok(Cell::BoolArray(to_cells(v, |e| e != 0)))
+        }),
+        SqlType::Int16 => read_column!(block, col, false, Vec<i16>, |v| {
+            Ok(Cell::I16Array(to_cells(v, |e| e)))
+        }),
+        SqlType::UInt16 => read_column!(block, col, false, Vec<u16>, |v| {
+            Ok(Cell::I32Array(to_cells(v, |e| e as i32)))
+        }),
+        SqlType::Int32 => read_column!(block, col, false, Vec<i32>, |v| {
+            Ok(Cell::I32Array(to_cells(v, |e| e)))
+        }),
+        SqlType::UInt32 => read_column!(block, col, false, Vec<u32>, |v| {
+            Ok(Cell::I64Array(to_cells(v, |e| e as i64)))
+        }),
+        SqlType::Int64 => read_column!(block, col, false, Vec<i64>, |v| {
+            Ok(Cell::I64Array(to_cells(v, |e| e)))
+        }),
+        // bigint[] cannot keep UInt64 values beyond bigint, so they are
+        // rejected instead of wrapping around
+        SqlType::UInt64 => read_column!(block, col, false, Vec<u64>, |v| {
+            v.into_iter()
+                .map(|e| {
+                    i64::try_from(e).map(Some).map_err(|_| {
+                        ClickHouseFdwError::NumericConversionError(format!(
+                            "{} is out of range for bigint",
+                            e
+                        ))
+                    })
+                })
+                .collect::<ClickHouseFdwResult<Vec<_>>>()
+                .map(Cell::I64Array)
+        }),
+        SqlType::Float32 => read_column!(block, col, false, Vec<f32>, |v| {
+            Ok(Cell::F32Array(to_cells(v, |e| e)))
+        }),
+        SqlType::Float64 => read_column!(block, col, false, Vec<f64>, |v| {
+            Ok(Cell::F64Array(to_cells(v, |e| e)))
+        }),
+        SqlType::String => read_column!(block, col, false, Vec<String>, |v| {
+            Ok(Cell::StringArray(to_cells(v, |e| e)))
+        }),
+        SqlType::Array(inner_type) => nested_array_column_to_cells(block, col, inner_type),
+        // an empty array constant, its text form can be read into any array
+        // type, see convert_cell()
+        SqlType::Nothing => (0..block.row_count())
+            .map(|_| Ok(Some(Cell::MdArray(pg_sys::TEXTARRAYOID, "{}".to_string()))))
+            .collect(),
+        _ => unsupported_column(block, &format!("Array({})", elem_type)),
+    }
+}
+
+// convert an Array(Array(T)) column to two-dimensional array cells in their
+// text form, the driver cannot decode a nested array into a single value so
+// the column is iterated as a whole
+fn nested_array_column_to_cells(
+    block: &Block,
+    col: usize,
+    elem_type: &SqlType,
+) -> Vec<ClickHouseFdwResult<Option<Cell>>> {
+    macro_rules! read_nested {
+        ($t:ty, $array_oid:expr, $f:expr) => {
+            match block.columns()[col].iter::<Vec<Vec<$t>>>() {
+                Ok(iter) => iter
+                    .map(|v| md_array_cell($array_oid, &v, $f).map(Some))
+                    .collect(),
+                Err(_) => unsupported_column(block, &format!("Array(Array({}))", elem_type)),
+            }
+        };
+    }
+    match elem_type {
+        // Bool is stored as UInt8 in ClickHouse, so we treat it as bool here
+        SqlType::UInt8 => read_nested!(u8, pg_sys::BOOLARRAYOID, |e: &&u8| {
+            (if **e != 0 { "t" } else { "f" }).to_string()
+        }),
+        SqlType::Int16 => read_nested!(i16, pg_sys::INT2ARRAYOID, |e: &&i16| e.to_string()),
+        SqlType::UInt16 => read_nested!(u16, pg_sys::INT4ARRAYOID, |e: &&u16| e.to_string()),
+        SqlType::Int32 => read_nested!(i32, pg_sys::INT4ARRAYOID, |e: &&i32| e.to_string()),
+        SqlType::UInt32 => read_nested!(u32, pg_sys::INT8ARRAYOID, |e: &&u32| e.to_string()),
+        SqlType::Int64 => read_nested!(i64, pg_sys::INT8ARRAYOID, |e: &&i64| e.to_string()),
+        SqlType::Float32 => read_nested!(f32, pg_sys::FLOAT4ARRAYOID, |e: &&f32| e.to_string()),
+        SqlType::Float64 => read_nested!(f64, pg_sys::FLOAT8ARRAYOID, |e: &&f64| e.to_string()),
+        _ => unsupported_column(block, &format!("Array(Array({}))", elem_type)),
+    }
+}
+
+// format a nested array as a Postgres multidimensional array, the inner arrays
+// must have the same length
+fn md_array_cell<T>(
+    array_oid: pg_sys::Oid,
+    value: &[Vec<T>],
+    f: impl Fn(&T) -> String,
+) -> ClickHouseFdwResult<Cell> {
+    if value.iter().any(|inner| inner.len() != value[0].len()) {
+        let lens = value
+            .iter()
+            .map(|inner| inner.len().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(ClickHouseFdwError::ArrayDimensionMismatch(format!(
+            "lengths {}",
+            lens
+        )));
+    }
+    // an array of empty arrays has no elements in Postgres
+    if value.iter().all(|inner| inner.is_empty()) {
+        return Ok(Cell::MdArray(array_oid, "{}".to_string()));
+    }
+    let inners = value
+        .iter()
+        .map(|inner| {
+            let elems = inner.iter().map(&f).collect::<Vec<_>>();
+            format!("{{{}}}", elems.join(","))
+        })
+        .collect::<Vec<_>>();
+    Ok(Cell::MdArray(
+        array_oid,
+        format!("{{{}}}", inners.join(",")),
+    ))
+}
+
+// every cell of an unsupported column is an error
+fn unsupported_column(block: &Block, sql_type: &str) -> Vec<ClickHouseFdwResult<Option<Cell>>> {
+    (0..block.row_count())
+        .map(|_| {
+            Err(ClickHouseFdwError::UnsupportedColumnType(
+                sql_type.to_owned(),
+            ))
+        })
+        .collect()
+}
+
+// name, type, nullability and default kind of a remote column
+type RemoteColumn = (String, String, bool, Option<String>);
+
+// get the argument type of a SimpleAggregateFunction type, such as `String` of
+// `SimpleAggregateFunction(anyLast, String)`, whose values are stored as the
+// argument type whatever the function is. Other types are returned as is.
+fn simple_agg_arg_type(sql_type: &str) -> &str {
+    sql_type
+        .strip_prefix("SimpleAggregateFunction(")
+        .and_then(|args| args.strip_suffix(')'))
+        .and_then(|args| args.split_once(','))
+        .map_or(sql_type, |(_, arg_type)| arg_type.trim())
+}
+
+// get the type of the values stored in a ClickHouse column type, such as
+// `String` of `LowCardinality(Nullable(String))`
+fn value_type(sql_type: &str) -> &str {
+    let mut sql_type = simple_agg_arg_type(sql_type);
+    for wrapper in ["LowCardinality(", "Nullable("] {
+        if let Some(inner) = sql_type
+            .strip_prefix(wrapper)
+            .and_then(|t| t.strip_suffix(')'))
+        {
+            sql_type = inner;
         }
-        SqlType::UInt32 => {
-            let value = row.get::<u32, usize>(i)?;
-            Ok(Some(Cell::I64(value as i64)))
+    }
+    sql_type
+}
+
+// check if values of a ClickHouse column type can be read into the column,
+// types not listed are assumed to be readable
+fn is_readable_type(col: &Column, sql_type: &str) -> bool {
+    let sql_type = value_type(sql_type);
+    let name = sql_type.split('(').next().unwrap_or(sql_type);
+    let is_decimal = name.starts_with("Decimal");
+    let is_enum = name == "Enum8" || name == "Enum16";
+    match col.type_oid {
+        // any type can be read as its text form
+        oid if is_text_type(oid) || is_citext_type(oid) => true,
+        pg_sys::XMLOID | pg_sys::JSONBOID | pg_sys::BYTEAOID => true,
+        pg_sys::BOOLOID => matches!(name, "UInt8" | "Bool" | "Int8"),
+        // enums are read as integers with the enum_as_int option
+        pg_sys::INT2OID => matches!(name, "Int8" | "UInt8" | "Int16") || is_enum,
+        pg_sys::INT4OID => {
+            matches!(name, "Int8" | "UInt8" | "Int16" | "UInt16" | "Int32") || is_enum
         }
-        SqlType::Float32 => {
-            let value = row.get::<f32, usize>(i)?;
-            Ok(Some(Cell::F32(value)))
+        pg_sys::INT8OID => {
+            matches!(
+                name,
+                "Int8" | "UInt8" | "Int16" | "UInt16" | "Int32" | "UInt32" | "Int64" | "UInt64"
+            ) || is_enum
         }
-        SqlType::Float64 => {
-            let value = row.get::<f64, usize>(i)?;
-            Ok(Some(Cell::F64(value)))
+        pg_sys::FLOAT4OID => name == "Float32",
+        pg_sys::FLOAT8OID => matches!(name, "Float32" | "Float64") || is_decimal,
+        pg_sys::NUMERICOID | pg_sys::MONEYOID => {
+            is_decimal || name.starts_with("Int") || name.starts_with("UInt")
         }
-        SqlType::UInt64 => {
-            let value = row.get::<u64, usize>(i)?;
-            Ok(Some(Cell::I64(value as i64)))
+        pg_sys::DATEOID => matches!(name, "Date" | "Date32"),
+        pg_sys::TIMESTAMPOID | pg_sys::TIMESTAMPTZOID => {
+            matches!(name, "DateTime" | "DateTime64")
         }
-        SqlType::Int64 => {
-            let value = row.get::<i64, usize>(i)?;
-            Ok(Some(Cell::I64(value)))
+        oid if is_enum_type(oid) => matches!(name, "String" | "FixedString") || is_enum,
+        oid if is_composite_type(oid) => name == "Tuple",
+        oid if range_subtype(oid).is_some() => matches!(name, "Tuple" | "Array"),
+        oid if unsafe { pg_sys::get_element_type(oid) } != pg_sys::InvalidOid => name == "Array",
+        _ => true,
+    }
+}
+
+// describe how the remote columns drifted from the columns of a foreign table,
+// None if they don't
+pub(super) fn schema_drift(columns: &[Column], remote_cols: &[RemoteColumn]) -> Option<String> {
+    let drifts = columns
+        .iter()
+        .filter_map(
+            |col| match remote_cols.iter().find(|(name, ..)| name == &col.name) {
+                None => Some(format!("column '{}' is removed", col.name)),
+                Some((_, sql_type, ..)) if !is_readable_type(col, sql_type) => {
+                    Some(format!("column '{}' is retyped to {}", col.name, sql_type))
+                }
+                _ => None,
+            },
+        )
+        .chain(
+            remote_cols
+                .iter()
+                .filter(|(name, ..)| !columns.iter().any(|col| &col.name == name))
+                .map(|(name, ..)| format!("column '{}' is added", name)),
+        )
+        .collect::<Vec<_>>();
+    (!drifts.is_empty()).then(|| drifts.join(", "))
+}
+
+// check if a ClickHouse column type can hold NULL
+fn is_nullable_type(sql_type: &str) -> bool {
+    let sql_type = simple_agg_arg_type(sql_type);
+    sql_type.starts_with("Nullable(") || sql_type.starts_with("LowCardinality(Nullable(")
+}
+
+// collations that order strings the same way as ClickHouse's byte-wise comparison
+fn is_pushable_collation(collate: &Option<String>) -> bool {
+    match collate {
+        None => true,
+        Some(collate) => matches!(collate.as_str(), "C" | "POSIX" | "ucs_basic"),
+    }
+}
+
+// check if ClickHouse sorts nulls the same way as Postgres. ClickHouse puts
+// NaN next to nulls while Postgres sorts NaN as the greatest value, so a sort
+// on float column only matches if nulls are placed after the greatest values,
+// which is `asc nulls last` or `desc nulls first`
+fn is_pushable_null_order(sort: &Sort, columns: &[Column]) -> bool {
+    let is_float = columns.iter().any(|c| {
+        c.name == sort.field && (c.type_oid == pg_sys::FLOAT4OID || c.type_oid == pg_sys::FLOAT8OID)
+    });
+    !is_float || sort.reversed == sort.nulls_first
+}
+
+// check if a connection error is transient, such as ClickHouse is restarting
+fn is_transient_connect_error(err: &clickhouse_rs::errors::Error) -> bool {
+    use clickhouse_rs::errors::{DriverError, Error};
+    matches!(
+        err,
+        Error::Io(_) | Error::Connection(_) | Error::Driver(DriverError::Timeout)
+    )
+}
+
+// run connect and retry it with exponential backoff if it failed with transient
+// error, at most `retries` times
+pub(super) fn connect_with_retry<T>(
+    retries: u32,
+    backoff_ms: u64,
+    mut connect: impl FnMut() -> Result<T, clickhouse_rs::errors::Error>,
+) -> ClickHouseFdwResult<T> {
+    let mut attempt = 0;
+    loop {
+        match connect() {
+            Ok(conn) => return Ok(conn),
+            Err(err) if attempt < retries && is_transient_connect_error(&err) => {
+                let backoff = backoff_ms * 2u64.pow(attempt);
+                attempt += 1;
+                log_debug1(&format!(
+                    "retry connect ({}/{}) in {}ms: {}",
+                    attempt, retries, backoff, err
+                ));
+                thread::sleep(Duration::from_millis(backoff));
+                pgrx::check_for_interrupts!();
+            }
+            Err(err) => return Err(err.into()),
         }
-        SqlType::String => {
-            let value = row.get::<String, usize>(i)?;
-            Ok(Some(Cell::String(value)))
+    }
+}
+
+// build the driver's connection options, TCP socket options not set are left
+// to the driver's defaults
+pub(super) fn conn_options(
+    conn_str: &str,
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive: Option<Duration>,
+) -> ClickHouseFdwResult<Options> {
+    let mut options = Options::from_str(conn_str)?;
+    if let Some(nodelay) = tcp_nodelay {
+        options = options.nodelay(nodelay);
+    }
+    if tcp_keepalive.is_some() {
+        options = options.keepalive(tcp_keepalive);
+    }
+    Ok(options)
+}
+
+// name of the semaphore limiting concurrent scans, servers with the same
+// connection string share it and the string is hashed to not keep the
+// credentials in it
+pub(super) fn scan_semaphore_name(conn_str: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    conn_str.hash(&mut hasher);
+    format!("{}:{:016x}", ClickHouseFdw::FDW_NAME, hasher.finish())
+}
+
+// build the mutation query, the distributed DDL timeout is only set when it
+// runs on a cluster
+pub(super) fn alter_table_sql(
+    table: &str,
+    on_cluster: Option<&str>,
+    ddl_task_timeout: Option<u64>,
+    action: &str,
+) -> String {
+    match (on_cluster, ddl_task_timeout) {
+        (Some(cluster), Some(timeout)) => format!(
+            "alter table {} on cluster '{}' {} settings distributed_ddl_task_timeout = {}",
+            table, cluster, action, timeout
+        ),
+        (Some(cluster), None) => {
+            format!("alter table {} on cluster '{}' {}", table, cluster, action)
         }
-        SqlType::Date => {
-            let value = row.get::<Date<_>, usize>(i)?;
+        (None, _) => format!("alter table {} {}", table, action),
+    }
+}
+
+// check the cluster name, which can be a name or a macro like `{cluster}`
+fn parse_cluster_name(name: &str) -> ClickHouseFdwResult<String> {
+    let re = Regex::new(r"^([A-Za-z0-9_.-]+|\{[A-Za-z0-9_]+\})$").unwrap();
+    if !re.is_match(name) {
+        return Err(ClickHouseFdwError::InvalidOption(
+            "on_cluster".to_string(),
+            name.to_owned(),
+        ));
+    }
+    Ok(name.to_owned())
+}
+
+// estimate the memory size of a cell buffered locally
+fn cell_size(cell: &Option<Cell>) -> usize {
+    let heap_size = match cell {
+        // multidimensional array, composite and range values are kept in
+        // their text form, which holds all their elements
+        Some(Cell::String(v))
+        | Some(Cell::Enum(_, v))
+        | Some(Cell::Uuid(v))
+        | Some(Cell::MdArray(_, v))
+        | Some(Cell::Composite(_, v))
+        | Some(Cell::Range(_, v)) => v.len(),
+        // numeric is stored as 2 bytes per 4 decimal digits after an 8-byte
+        // header
+        Some(Cell::Numeric(v)) => 8 + v.to_string().len().div_ceil(4) * 2,
+        Some(Cell::Json(v)) => v.0.to_string().len(),
+        Some(Cell::Bytea(v)) => v.len(),
+        Some(Cell::BoolArray(v)) => v.len() * std::mem::size_of::<Option<bool>>(),
+        Some(Cell::I16Array(v)) => v.len() * std::mem::size_of::<Option<i16>>(),
+        Some(Cell::I32Array(v)) => v.len() * std::mem::size_of::<Option<i32>>(),
+        Some(Cell::I64Array(v)) => v.len() * std::mem::size_of::<Option<i64>>(),
+        Some(Cell::F32Array(v)) => v.len() * std::mem::size_of::<Option<f32>>(),
+        Some(Cell::F64Array(v)) => v.len() * std::mem::size_of::<Option<f64>>(),
+        Some(Cell::StringArray(v)) => v
+            .iter()
+            .map(|e| std::mem::size_of::<Option<String>>() + e.as_ref().map_or(0, |e| e.len()))
+            .sum(),
+        _ => 0,
+    };
+    std::mem::size_of::<Option<Cell>>() + heap_size
+}
+
+// check if a datetime format is valid strftime format
+fn is_valid_datetime_format(fmt: &str) -> bool {
+    !StrftimeItems::new(fmt).any(|item| matches!(item, Item::Error))
+}
+
+// format a Date or DateTime cell to string, DateTime is formatted in the default
+// timezone
+fn format_datetime(cell: Cell, fmt: &str, default_tz: Tz) -> ClickHouseFdwResult<Cell> {
+    let formatted = match cell {
+        Cell::Date(v) => {
             let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
-            let seconds_from_epoch = value.naive_utc().signed_duration_since(epoch).num_seconds();
-            let ts = to_timestamp(seconds_from_epoch as f64);
-            Ok(Some(Cell::Date(pgrx::Date::from(ts))))
+            let date = epoch + chrono::Duration::days(v.to_unix_epoch_days() as i64);
+            date.format(fmt).to_string()
+        }
+        Cell::Timestamp(v) => {
+            // Postgres timestamp is microseconds since 2000-01-01
+            const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
+            let micros = v.into_inner() + PG_EPOCH_MICROS;
+            let ts = Utc
+                .timestamp_opt(
+                    micros.div_euclid(1_000_000),
+                    (micros.rem_euclid(1_000_000) * 1000) as u32,
+                )
+                .single()
+                .ok_or_else(|| ClickHouseFdwError::DatetimeConversionError(v.to_string()))?;
+            ts.with_timezone(&default_tz).format(fmt).to_string()
+        }
+        _ => {
+            return Err(ClickHouseFdwError::DatetimeConversionError(format!(
+                "cannot format non-datetime value {}",
+                cell
+            )))
+        }
+    };
+    Ok(Cell::String(formatted))
+}
+
+// parse ClickHouse 'YYYY-MM-DD' date text to date cell, Date32 can be earlier
+// than 1970 or later than 2149 which is beyond Date's range
+fn parse_date(s: &str) -> ClickHouseFdwResult<Cell> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
+    pgrx::Date::new(date.year(), date.month() as u8, date.day() as u8)
+        .map(Cell::Date)
+        .map_err(|err| ClickHouseFdwError::DatetimeConversionError(format!("{}: {}", s, err)))
+}
+
+// check if a Nested column is read as jsonb array of objects
+fn is_nested_as_json(col: &Column) -> bool {
+    col.options.get("nested_as_json") == Some(&"true".to_string())
+}
+
+// check if the type is a plain text type, citext is excluded
+fn is_text_type(type_oid: pg_sys::Oid) -> bool {
+    [pg_sys::TEXTOID, pg_sys::VARCHAROID, pg_sys::BPCHAROID].contains(&type_oid)
+}
+
+// remove `with totals` from a subquery, because the driver returns the totals
+// row as a data row and it cannot be told apart. String literals, quoted
+// identifiers and comments are kept as is.
+fn strip_totals(sql: &str) -> String {
+    let re = Regex::new(
+        r#"(?is)'(?:[^'\\]|\\.|'')*'|"(?:[^"\\]|\\.)*"|`(?:[^`\\]|\\.)*`|--[^\n]*|/\*.*?\*/|\bwith\s+totals\b"#,
+    )
+    .unwrap();
+    re.replace_all(sql, |caps: &Captures| {
+        let m = &caps[0];
+        if m.len() >= 4 && m[..4].eq_ignore_ascii_case("with") {
+            String::new()
+        } else {
+            m.to_string()
+        }
+    })
+    .into_owned()
+}
+
+// parse a settings option, a comma separated list of `name = value`, to
+// `name = value` strings for the query's settings clause. Value can be a number,
+// a boolean, a quoted string, or a bare word which is quoted as string.
+fn parse_query_settings(option: &str, settings: &str) -> ClickHouseFdwResult<Vec<String>> {
+    let invalid = || ClickHouseFdwError::InvalidOption(option.to_string(), settings.to_owned());
+    let re = Regex::new(
+        r"^\s*([A-Za-z_][A-Za-z0-9_]*)\s*=\s*('(?:[^'\\]|\\.)*'|[A-Za-z0-9_.+-]+)\s*(?:,|$)",
+    )
+    .unwrap();
+    let number = Regex::new(r"^[+-]?[0-9]+(\.[0-9]+)?$").unwrap();
+    let mut result = Vec::new();
+    let mut rest = settings;
+    while !rest.trim().is_empty() {
+        let caps = re.captures(rest).ok_or_else(invalid)?;
+        let value = &caps[2];
+        let value = if value.starts_with('\'')
+            || number.is_match(value)
+            || value == "true"
+            || value == "false"
+        {
+            value.to_owned()
+        } else {
+            format!("'{}'", value)
+        };
+        result.push(format!("{} = {}", &caps[1], value));
+        rest = &rest[caps[0].len()..];
+    }
+    if result.is_empty() {
+        return Err(invalid());
+    }
+    Ok(result)
+}
+
+// quote a string as ClickHouse string literal
+fn quote_string(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+// quote an identifier with backticks
+fn quote_identifier(ident: &str) -> String {
+    format!("`{}`", ident.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+// check if a Variant column is read as jsonb object tagged with the active type
+fn is_variant_as_json(col: &Column) -> bool {
+    col.options.get("variant_as_json") == Some(&"true".to_string())
+}
+
+// get the ClickHouse integer type an enum column is converted to if it is read
+// as integer values, it follows the Postgres integer type of the column
+fn enum_int_type(col: &Column) -> Option<&'static str> {
+    if col.options.get("enum_as_int") != Some(&"true".to_string()) {
+        return None;
+    }
+    match col.type_oid {
+        pg_sys::INT2OID => Some("Int16"),
+        pg_sys::INT4OID => Some("Int32"),
+        pg_sys::INT8OID => Some("Int64"),
+        pg_sys::INT2ARRAYOID => Some("Array(Int16)"),
+        pg_sys::INT4ARRAYOID => Some("Array(Int32)"),
+        pg_sys::INT8ARRAYOID => Some("Array(Int64)"),
+        _ => None,
+    }
+}
+
+// check if an Int8 column is read as boolean
+fn is_int8_as_bool(col: &Column) -> bool {
+    col.options.get("int8_as_bool") == Some(&"true".to_string())
+}
+
+// get the letter case, `lower` or `upper`, of the UUID values read into a text
+// column, ClickHouse formats UUIDs in lowercase
+fn uuid_case(col: &Column) -> Option<&str> {
+    col.options.get("uuid_case").map(|case| case.as_str())
+}
+
+// check if a Decimal column is read as float values instead of numeric
+fn is_decimal_as_float(col: &Column) -> bool {
+    col.options.get("decimal_as_float") == Some(&"true".to_string())
+}
+
+// read a Decimal column as float values, which skips the numeric conversion
+// but can lose precision
+fn decimal_column_to_floats(
+    block: &Block,
+    col: usize,
+) -> ClickHouseFdwResult<Vec<ClickHouseFdwResult<Option<Cell>>>> {
+    match block.columns()[col].sql_type() {
+        SqlType::Decimal(..) => Ok(read_column!(block, col, false, types::Decimal, |v| {
+            Ok(Cell::F64(f64::from(v)))
+        })),
+        SqlType::Nullable(SqlType::Decimal(..)) => {
+            Ok(read_column!(block, col, true, types::Decimal, |v| {
+                Ok(Cell::F64(f64::from(v)))
+            }))
         }
-        SqlType::DateTime(_) => {
-            let value = row.get::<DateTime<_>, usize>(i)?;
-            let ts = to_timestamp(value.timestamp() as f64);
-            Ok(Some(Cell::Timestamp(ts.to_utc())))
+        sql_type => Err(ClickHouseFdwError::UnsupportedColumnType(format!(
+            "{} with decimal_as_float",
+            sql_type
+        ))),
+    }
+}
+
+// check if a column is declared as a partition key of the ClickHouse table
+fn is_partition_key(col: &Column) -> bool {
+    col.options.get("partition_key") == Some(&"true".to_string())
+}
+
+// check if the sub-microsecond part of a DateTime64 column is rounded to the
+// nearest microsecond, it is truncated by default
+fn is_subsecond_round(col: &Column) -> bool {
+    col.options.get("subsecond_rounding") == Some(&"round".to_string())
+}
+
+// read a DateTime64 column as timestamps rounded to the nearest microsecond,
+// half a microsecond is rounded up, other columns are read as usual
+fn datetime_column_to_rounded_timestamps(
+    block: &Block,
+    col: usize,
+) -> Vec<ClickHouseFdwResult<Option<Cell>>> {
+    let to_cell = |v: DateTime<Tz>| {
+        let micros = v.timestamp_micros() + i64::from(v.timestamp_subsec_nanos() % 1000 >= 500);
+        let ts = to_timestamp(micros as f64 / 1_000_000.0);
+        Ok(Cell::Timestamp(ts.to_utc()))
+    };
+    match block.columns()[col].sql_type() {
+        SqlType::DateTime(_) => read_column!(block, col, false, DateTime<_>, to_cell),
+        SqlType::Nullable(SqlType::DateTime(_)) => {
+            read_column!(block, col, true, DateTime<_>, to_cell)
         }
-        _ => Err(ClickHouseFdwError::UnsupportedColumnType(
-            sql_type.to_string().into(),
+        _ => column_to_cells(block, col),
+    }
+}
+
+// jsonb values of a nested or variant column, enum integer values, Int8
+// booleans, floats of decimals, rounded timestamps, formatted datetime text,
+// composite and range values cannot be compared on ClickHouse as they are in
+// Postgres, and computed columns don't exist on ClickHouse, so quals and sorts
+// on them are left to Postgres
+fn is_local_column(col: &Column) -> bool {
+    is_nested_as_json(col)
+        || is_variant_as_json(col)
+        || is_geo_as_json(col)
+        || is_computed(col)
+        || enum_int_type(col).is_some()
+        || is_int8_as_bool(col)
+        || is_decimal_as_float(col)
+        || is_subsecond_round(col)
+        || col.options.contains_key("datetime_format")
+        || is_composite_type(col.type_oid)
+        || tuple_range_bounds(col).is_some()
+        || is_enum_type(col.type_oid)
+        || uuid_case(col).is_some()
+}
+
+// get the bounds, such as `[)`, of a range column read from a two-element tuple
+// or array
+fn tuple_range_bounds(col: &Column) -> Option<&str> {
+    col.options
+        .get("tuple_as_range")
+        .map(|bounds| bounds.as_str())
+}
+
+// convert a two-element tuple or array read as json by deparse() to a range
+// cell, each element must be a value of the range's subtype and null makes the
+// bound unbounded
+fn json_to_range(
+    s: &str,
+    tgt_col: &Column,
+    bounds: &str,
+    default_tz: Tz,
+) -> ClickHouseFdwResult<Cell> {
+    let invalid = || ClickHouseFdwError::InvalidRangeValue(tgt_col.name.clone(), s.to_owned());
+    let subtype = range_subtype(tgt_col.type_oid)
+        .ok_or_else(|| ClickHouseFdwError::RangeColumnType(tgt_col.name.clone()))?;
+    let elems: Vec<serde_json::Value> = serde_json::from_str(s).map_err(|_| invalid())?;
+    if elems.len() != 2 {
+        return Err(invalid());
+    }
+
+    let to_bound = |elem: &serde_json::Value| -> ClickHouseFdwResult<String> {
+        let v = match elem {
+            serde_json::Value::Null => return Ok(String::new()),
+            serde_json::Value::String(v) => v.to_owned(),
+            serde_json::Value::Number(v) => v.to_string(),
+            _ => return Err(invalid()),
+        };
+        let parse_datetime = |v: &str| NaiveDateTime::parse_from_str(v, "%Y-%m-%d %H:%M:%S%.f");
+        let v = if subtype == pg_sys::INT4OID {
+            v.parse::<i32>().map(|_| v).map_err(|_| invalid())?
+        } else if subtype == pg_sys::INT8OID {
+            v.parse::<i64>().map(|_| v).map_err(|_| invalid())?
+        } else if subtype == pg_sys::NUMERICOID {
+            AnyNumeric::try_from(v.as_str())
+                .map(|_| v)
+                .map_err(|_| invalid())?
+        } else if subtype == pg_sys::DATEOID {
+            NaiveDate::parse_from_str(&v, "%Y-%m-%d")
+                .map(|_| v)
+                .map_err(|_| invalid())?
+        } else if subtype == pg_sys::TIMESTAMPOID {
+            parse_datetime(&v).map(|_| v).map_err(|_| invalid())?
+        } else if subtype == pg_sys::TIMESTAMPTZOID {
+            // DateTime has no timezone in its text form, so it's taken as in
+            // the default timezone
+            let ts = parse_datetime(&v).map_err(|_| invalid())?;
+            default_tz
+                .from_local_datetime(&ts)
+                .earliest()
+                .ok_or_else(invalid)?
+                .format("%Y-%m-%d %H:%M:%S%.f%:z")
+                .to_string()
+        } else {
+            // other subtypes are checked by the range type's input function
+            v
+        };
+        Ok(format!(
+            "\"{}\"",
+            v.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+    };
+
+    let (lower, upper) = bounds.split_at(1);
+    Ok(Cell::Range(
+        tgt_col.type_oid,
+        format!(
+            "{}{},{}{}",
+            lower,
+            to_bound(&elems[0])?,
+            to_bound(&elems[1])?,
+            upper
+        ),
+    ))
+}
+
+// check if a geo column is read as GeoJSON-like jsonb object
+fn is_geo_as_json(col: &Column) -> bool {
+    col.options.get("geo_as_json") == Some(&"true".to_string())
+}
+
+// convert a named tuple read as json by deparse() to a composite cell, the tuple
+// element names must match the composite type fields in the same order
+fn tuple_to_composite(s: &str, tgt_col: &Column, fields: &[String]) -> ClickHouseFdwResult<Cell> {
+    let (names, values): (Vec<String>, serde_json::Map<String, serde_json::Value>) =
+        serde_json::from_str(s)?;
+    if names != fields {
+        return Err(ClickHouseFdwError::CompositeTypeMismatch(
+            tgt_col.name.clone(),
+            names.join(", "),
+            fields.join(", "),
+        ));
+    }
+    let quote = |v: &str| format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""));
+    let elems = names
+        .iter()
+        .map(|name| match values.get(name) {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(v)) => quote(v),
+            Some(v) => quote(&v.to_string()),
+        })
+        .collect::<Vec<String>>();
+    Ok(Cell::Composite(
+        tgt_col.type_oid,
+        format!("({})", elems.join(",")),
+    ))
+}
+
+// convert a geo value serialized as {"type": <ClickHouse type>, "coordinates": ...}
+// to GeoJSON-like object with GeoJSON geometry type
+fn geo_to_json(s: &str) -> ClickHouseFdwResult<serde_json::Value> {
+    let mut value: serde_json::Value = serde_json::from_str(s)?;
+    let ch_type = value["type"].as_str().unwrap_or_default().to_owned();
+    let geo_type = match ch_type.as_str() {
+        "Point" => "Point",
+        "Array(Point)" => "MultiPoint",
+        "Ring" | "LineString" => "LineString",
+        "Array(Ring)" | "Array(LineString)" | "MultiLineString" => "MultiLineString",
+        "Polygon" => "Polygon",
+        "Array(Polygon)" | "MultiPolygon" => "MultiPolygon",
+        _ => return Err(ClickHouseFdwError::UnsupportedColumnType(ch_type)),
+    };
+    value["type"] = serde_json::Value::from(geo_type);
+    Ok(value)
+}
+
+// check if the text is well-formed xml, as a document or content depending on
+// the xmloption setting
+fn is_well_formed_xml(s: &str) -> bool {
+    unsafe { fcinfo::direct_function_call::<bool>(pg_sys::xml_is_well_formed, &[s.into_datum()]) }
+        .unwrap_or(false)
+}
+
+// convert a numeric cell to Postgres money, which is stored as integer cents
+fn cell_to_money(cell: Cell) -> ClickHouseFdwResult<Cell> {
+    let value = match cell {
+        Cell::Numeric(v) => v,
+        Cell::F32(v) => AnyNumeric::try_from(v)
+            .map_err(|err| ClickHouseFdwError::NumericConversionError(err.to_string()))?,
+        Cell::F64(v) => AnyNumeric::try_from(v)
+            .map_err(|err| ClickHouseFdwError::NumericConversionError(err.to_string()))?,
+        Cell::I16(v) => AnyNumeric::from(v),
+        Cell::I32(v) => AnyNumeric::from(v),
+        Cell::I64(v) => AnyNumeric::from(v),
+        _ => {
+            return Err(ClickHouseFdwError::UnsupportedColumnType(
+                "money".to_string(),
+            ))
+        }
+    };
+    let cents =
+        unsafe { fcinfo::direct_function_call::<i64>(pg_sys::numeric_cash, &[value.into_datum()]) };
+    cents
+        .map(Cell::I64)
+        .ok_or_else(|| ClickHouseFdwError::NumericConversionError("money".to_string()))
+}
+
+// decode hex text to bytes
+fn decode_hex(s: &str) -> ClickHouseFdwResult<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(ClickHouseFdwError::BinaryConversionError(s.to_owned()));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| ClickHouseFdwError::BinaryConversionError(s.to_owned()))
+        })
+        .collect()
+}
+
+// get the width of a FixedString type, including the nullable and low
+// cardinality ones
+fn fixed_string_width(sql_type: &str) -> Option<usize> {
+    value_type(sql_type)
+        .strip_prefix("FixedString(")?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
+}
+
+// convert a source cell to cell which matches the target column type
+fn convert_cell(
+    cell: Option<Cell>,
+    tgt_col: &Column,
+    default_tz: Tz,
+) -> ClickHouseFdwResult<Option<Cell>> {
+    if let Some(Cell::String(s)) = &cell {
+        if s.is_empty() && tgt_col.options.get("empty_string_as_null") == Some(&"true".to_string())
+        {
+            return Ok(None);
+        }
+    }
+    if is_enum_type(tgt_col.type_oid) {
+        // labels not in the enum type fail the scan, or are read as null with
+        // the unknown_enum_label column option
+        if let Some(Cell::String(label)) = cell {
+            if is_enum_label(tgt_col.type_oid, &label) {
+                return Ok(Some(Cell::Enum(tgt_col.type_oid, label)));
+            }
+            if tgt_col.options.get("unknown_enum_label") == Some(&"null".to_string()) {
+                return Ok(None);
+            }
+            return Err(ClickHouseFdwError::UnknownEnumLabel(
+                tgt_col.name.clone(),
+                label,
+            ));
+        }
+    }
+    if tgt_col.type_oid == pg_sys::MONEYOID {
+        return cell.map(cell_to_money).transpose();
+    }
+    if tgt_col.type_oid == pg_sys::NUMERICOID {
+        // integers are widened to numeric
+        match cell {
+            Some(Cell::I16(v)) => return Ok(Some(Cell::Numeric(AnyNumeric::from(v)))),
+            Some(Cell::I32(v)) => return Ok(Some(Cell::Numeric(AnyNumeric::from(v)))),
+            Some(Cell::I64(v)) => return Ok(Some(Cell::Numeric(AnyNumeric::from(v)))),
+            _ => {}
+        }
+    }
+    if tgt_col.type_oid == pg_sys::INT8OID {
+        // UInt64 values beyond bigint are read as numeric, see column_to_cells()
+        if let Some(Cell::Numeric(v)) = &cell {
+            return Err(ClickHouseFdwError::NumericConversionError(format!(
+                "{} is out of range for bigint",
+                v
+            )));
+        }
+    }
+    if let Some(Cell::MdArray(_, v)) = cell {
+        // the text form is parsed by the target array type's input function, so
+        // the elements can be read into another element type, such as integer
+        let elem_oid = unsafe { pg_sys::get_element_type(tgt_col.type_oid) };
+        if elem_oid == pg_sys::InvalidOid {
+            return Err(ClickHouseFdwError::UnsupportedColumnType(format!(
+                "multidimensional array for column '{}'",
+                tgt_col.name
+            )));
+        }
+        return Ok(Some(Cell::MdArray(tgt_col.type_oid, v)));
+    }
+    if tgt_col.type_oid == pg_sys::XMLOID {
+        // xml is read as text, which must be well-formed to be an xml value
+        if let Some(Cell::String(s)) = &cell {
+            if !is_well_formed_xml(s) {
+                return Err(ClickHouseFdwError::InvalidXml(tgt_col.name.clone()));
+            }
+        }
+    }
+    if let Some(fmt) = tgt_col.options.get("datetime_format") {
+        return cell
+            .map(|cell| format_datetime(cell, fmt, default_tz))
+            .transpose();
+    }
+    if tgt_col.type_oid == pg_sys::DATEOID {
+        // Date and Date32 are read as text, see deparse()
+        if let Some(Cell::String(s)) = cell {
+            return parse_date(&s).map(Some);
+        }
+    }
+    if tgt_col.type_oid == pg_sys::BYTEAOID {
+        // bytea is read as hex text, see deparse()
+        if let Some(Cell::String(s)) = cell {
+            return decode_hex(&s).map(|v| Some(Cell::Bytea(v)));
+        }
+    }
+    if is_geo_as_json(tgt_col) {
+        if let Some(Cell::String(s)) = cell {
+            return Ok(Some(Cell::Json(JsonB(geo_to_json(&s)?))));
+        }
+    }
+    if let Some(bounds) = tuple_range_bounds(tgt_col) {
+        // tuples and arrays are read as json string by toJSONString()
+        if let Some(Cell::String(s)) = cell {
+            return json_to_range(&s, tgt_col, bounds, default_tz).map(Some);
+        }
+    }
+    if is_nested_as_json(tgt_col) || is_variant_as_json(tgt_col) {
+        // nested and variant columns are read as json string by toJSONString()
+        if let Some(Cell::String(s)) = cell {
+            let value = serde_json::from_str(&s)?;
+            return Ok(Some(Cell::Json(JsonB(value))));
+        }
+    }
+    if tgt_col.type_oid == pg_sys::TIMESTAMPOID && default_tz != Tz::UTC {
+        // timestamp has no timezone, so shift it from UTC to the default timezone
+        if let Some(Cell::Timestamp(ts)) = cell {
+            let ts = TimestampWithTimeZone::try_from(ts.into_inner())
+                .map_err(|err| ClickHouseFdwError::DatetimeConversionError(err.to_string()))?
+                .at_timezone(default_tz.name())
+                .map_err(|err| ClickHouseFdwError::DatetimeConversionError(err.to_string()))?;
+            return Ok(Some(Cell::Timestamp(ts)));
+        }
+    }
+    if tgt_col.type_oid == pg_sys::TIMESTAMPTZOID {
+        // DateTime is read as UTC timestamp, which has the same microseconds
+        // representation as timestamptz
+        if let Some(Cell::Timestamp(ts)) = cell {
+            let tstz = TimestampWithTimeZone::try_from(ts.into_inner())
+                .map_err(|err| ClickHouseFdwError::DatetimeConversionError(err.to_string()))?;
+            return Ok(Some(Cell::Timestamptz(tstz)));
+        }
+    }
+    Ok(cell)
+}
+
+// convert a temporal cell to ClickHouse DateTime64 literal with explicit timezone,
+// ClickHouse will then convert it to the column's timezone when comparing
+fn temporal_literal(cell: &Cell, default_tz: Tz) -> Option<String> {
+    let (ts, tz) = match cell {
+        // timestamp is read from DateTime column in the default timezone, so
+        // treat it in the same timezone as well
+        Cell::Timestamp(v) => (*v, default_tz),
+        Cell::Timestamptz(v) => (v.to_utc(), Tz::UTC),
+        _ => return None,
+    };
+    if !ts.is_finite() {
+        return None;
+    }
+    let (hour, minute, second, micros) = ts.to_hms_micro();
+    Some(format!(
+        "toDateTime64('{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}', 6, '{}')",
+        ts.year(),
+        ts.month(),
+        ts.day(),
+        hour,
+        minute,
+        second,
+        micros,
+        tz.name()
+    ))
+}
+
+// convert a date cell to ClickHouse Date32 literal, so it doesn't depend on
+// the DateStyle setting
+fn date_literal(cell: &Cell) -> Option<String> {
+    match cell {
+        Cell::Date(v) if v.is_finite() => Some(format!(
+            "toDate32('{:04}-{:02}-{:02}')",
+            v.year(),
+            v.month(),
+            v.day()
         )),
+        _ => None,
+    }
+}
+
+// convert a Postgres timestamp to chrono datetime
+fn to_naive_datetime(ts: Timestamp) -> Option<NaiveDateTime> {
+    if !ts.is_finite() {
+        return None;
+    }
+    let (hour, minute, second, micros) = ts.to_hms_micro();
+    NaiveDate::from_ymd_opt(ts.year(), ts.month() as u32, ts.day() as u32)?.and_hms_micro_opt(
+        hour as u32,
+        minute as u32,
+        second as u32,
+        micros,
+    )
+}
+
+// convert a datetime to DateTime block value, the driver stores it as seconds
+// in u32, so reject values out of that range instead of wrapping them
+fn datetime_value(tm: Option<DateTime<Utc>>, cell: &Cell) -> ClickHouseFdwResult<types::Value> {
+    tm.filter(|tm| u32::try_from(tm.timestamp()).is_ok())
+        .map(types::Value::from)
+        .ok_or_else(|| {
+            ClickHouseFdwError::DatetimeConversionError(format!(
+                "timestamp {} is out of ClickHouse DateTime range",
+                cell
+            ))
+        })
+}
+
+// convert a numeric cell to ClickHouse Decimal literal with the value's own scale,
+// a plain numeric literal is parsed as Float64 by ClickHouse which loses precision
+// when it is compared with or stored into Decimal column
+fn numeric_literal(cell: &Cell) -> Option<String> {
+    let value = match cell {
+        Cell::Numeric(v) => v.to_string(),
+        _ => return None,
+    };
+    let digits = value.trim_start_matches('-');
+    if !digits.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        // NaN and Infinity have no Decimal representation
+        return None;
+    }
+    let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+    let scale = frac_part.len();
+    if int_part.trim_start_matches('0').len() + scale > 38 {
+        // out of the precision of Decimal128
+        return None;
+    }
+    Some(format!("toDecimal128('{}', {})", value, scale))
+}
+
+// convert a bytea cell to ClickHouse string literal of the raw bytes
+fn bytea_literal(cell: &Cell) -> Option<String> {
+    match cell {
+        Cell::Bytea(v) => Some(format!(
+            "unhex('{}')",
+            v.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        )),
+        _ => None,
+    }
+}
+
+// convert a cell to ClickHouse literal which keeps its exact value, return None
+// if the cell's default literal can be used
+fn typed_literal(cell: &Cell, default_tz: Tz) -> Option<String> {
+    temporal_literal(cell, default_tz)
+        .or_else(|| date_literal(cell))
+        .or_else(|| numeric_literal(cell))
+        .or_else(|| bytea_literal(cell))
+}
+
+// convert a cell to ClickHouse literal of an inserted value
+fn insert_literal(cell: &Option<Cell>, default_tz: Tz) -> String {
+    match cell {
+        None => "null".to_string(),
+        Some(Cell::String(s)) | Some(Cell::Enum(_, s)) | Some(Cell::Uuid(s)) => quote_string(s),
+        Some(Cell::Json(v)) => quote_string(&v.0.to_string()),
+        Some(cell) => typed_literal(cell, default_tz).unwrap_or_else(|| cell.to_string()),
+    }
+}
+
+// deparse a qual on timestamp, timestamptz or numeric value with typed literals
+fn deparse_typed_qual(qual: &Qual, default_tz: Tz) -> Option<String> {
+    match &qual.value {
+        Value::Cell(cell) => {
+            let literal = typed_literal(cell, default_tz)?;
+            Some(format!("{} {} {}", qual.field, qual.operator, literal))
+        }
+        Value::Array(cells) if qual.use_or => {
+            let conds = cells
+                .iter()
+                .map(|cell| {
+                    typed_literal(cell, default_tz)
+                        .map(|literal| format!("{} {} {}", qual.field, qual.operator, literal))
+                })
+                .collect::<Option<Vec<String>>>()?;
+            Some(conds.join(" or "))
+        }
+        Value::Array(_) => None,
+    }
+}
+
+// deparse a qual on citext column to a case-insensitive predicate
+fn deparse_citext_qual(qual: &Qual) -> String {
+    if qual.operator == "is" || qual.operator == "is not" {
+        return qual.deparse();
+    }
+    let lower = |cell: &Cell| match cell {
+        Cell::String(s) => Cell::String(s.to_lowercase()),
+        _ => cell.clone(),
+    };
+    let value = match &qual.value {
+        Value::Cell(cell) => Value::Cell(lower(cell)),
+        Value::Array(cells) => Value::Array(cells.iter().map(lower).collect()),
+    };
+    let qual = Qual {
+        field: format!("lowerUTF8({})", qual.field),
+        value,
+        ..qual.clone()
+    };
+    qual.deparse()
+}
+
+// find the SQL rewriter function named in the sql_rewriter option, which takes
+// and returns text
+fn lookup_sql_rewriter(name: &str) -> ClickHouseFdwResult<pg_sys::Oid> {
+    Spi::get_one_with_args::<pg_sys::Oid>(
+        "select p.oid from pg_proc p
+         where p.oid = to_regprocedure($1 || '(text)') and p.prorettype = 'text'::regtype",
+        vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())],
+    )
+    .ok()
+    .flatten()
+    .ok_or_else(|| ClickHouseFdwError::InvalidOption("sql_rewriter".to_string(), name.to_owned()))
+}
+
+// call the SQL rewriter function with the deparsed query, its output is
+// executed on ClickHouse instead
+fn rewrite_sql(rewriter: Option<pg_sys::Oid>, sql: &str) -> String {
+    match rewriter {
+        Some(func) => unsafe {
+            let datum =
+                pg_sys::OidFunctionCall1Coll(func, pg_sys::InvalidOid, sql.into_datum().unwrap());
+            String::from_datum(datum, false).unwrap_or_default()
+        },
+        None => sql.to_owned(),
+    }
+}
+
+// row callback function named in the row_callback option, which transforms
+// each scanned row before it's returned to Postgres
+#[derive(Debug, Clone, Copy)]
+struct RowCallback {
+    func: pg_sys::Oid,
+    // the composite type the function takes and returns
+    row_type: pg_sys::Oid,
+}
+
+// find the row callback function, which takes and returns the same composite
+// type, usually the row type of the foreign table
+fn lookup_row_callback(name: &str) -> ClickHouseFdwResult<RowCallback> {
+    let found = Spi::get_two_with_args::<pg_sys::Oid, pg_sys::Oid>(
+        "select p.oid, p.prorettype from pg_proc p join pg_type t on t.oid = p.prorettype
+         where p.oid = to_regproc($1) and p.pronargs = 1
+           and p.proargtypes[0] = p.prorettype and t.typtype = 'c'",
+        vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())],
+    );
+    match found {
+        Ok((Some(func), Some(row_type))) => Ok(RowCallback { func, row_type }),
+        _ => Err(ClickHouseFdwError::InvalidOption(
+            "row_callback".to_string(),
+            name.to_owned(),
+        )),
+    }
+}
+
+// call the row callback with the row as a value of its composite type, whose
+// fields not in the row are null, and read the row's columns back from the
+// returned value
+fn call_row_callback(callback: RowCallback, row: &mut Row) -> ClickHouseFdwResult<()> {
+    let callback_err = |err: String| ClickHouseFdwError::RowCallbackError(err);
+    let tupdesc = PgTupleDesc::for_composite_type_by_oid(callback.row_type)
+        .ok_or_else(|| callback_err(format!("type {} is not found", callback.row_type)))?;
+    let datums = tupdesc
+        .iter()
+        .map(|attr| {
+            row.cols
+                .iter()
+                .position(|col| !attr.attisdropped && col == attr.name())
+                .and_then(|idx| row.cells[idx].clone())
+                .and_then(|cell| cell.into_datum())
+        })
+        .collect::<Vec<_>>();
+    unsafe {
+        let arg = PgHeapTuple::from_datums(tupdesc, datums)
+            .map_err(|err| callback_err(err.to_string()))?
+            .into_composite_datum()
+            .ok_or_else(|| callback_err("cannot build the row value".to_string()))?;
+        let result = pg_sys::OidFunctionCall1Coll(callback.func, pg_sys::InvalidOid, arg);
+        let result = PgHeapTuple::from_composite_datum(result);
+        for (col, cell) in row.cols.iter().zip(row.cells.iter_mut()) {
+            *cell = result
+                .get_by_name::<Cell>(col)
+                .map_err(|err| callback_err(err.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Statistics of a scan's queries reported by ClickHouse
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    /// number of finished scan queries
+    pub queries: u64,
+    /// rows and bytes read by ClickHouse
+    pub read_rows: u64,
+    pub read_bytes: u64,
+    /// execution time on ClickHouse in milliseconds
+    pub server_ms: u64,
+    /// time to fetch and convert the result locally in milliseconds
+    pub fetch_ms: u64,
+}
+
+static LAST_SCAN_STATS: RwLock<Option<ScanStats>> = RwLock::new(None);
+
+/// Get the statistics of the last scan with the `log_server_stats` option in
+/// this backend, `None` if there is no such scan.
+#[allow(dead_code)]
+pub fn last_scan_stats() -> Option<ScanStats> {
+    LAST_SCAN_STATS.read().unwrap().clone()
+}
+
+// deparsed scan query without the literals of quals and limit, it is cached
+// for the query shape and filled with the literals of each scan
+#[derive(Debug, Clone)]
+struct Statement {
+    // select list, from and sample clauses
+    head: String,
+    // if each qual is pushed down, the others are filtered locally
+    pushed: Vec<bool>,
+    order_by: Option<String>,
+    limit_pushed: bool,
+    // settings clause
+    settings: String,
+}
+
+// max number of statements cached in a backend, the cache is cleared when full
+const STATEMENT_CACHE_SIZE: usize = 256;
+
+static STATEMENT_CACHE: Mutex<BTreeMap<String, Statement>> = Mutex::new(BTreeMap::new());
+
+// number of statements deparsed and reused from the cache by this backend
+static STATEMENTS_DEPARSED: AtomicU64 = AtomicU64::new(0);
+static STATEMENTS_REUSED: AtomicU64 = AtomicU64::new(0);
+
+/// Statistics of the statement cache of ClickHouse scans
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    /// number of scan queries deparsed from scratch
+    pub deparsed: u64,
+    /// number of scan queries reused from the cache with new literals
+    pub reused: u64,
+}
+
+/// Get the statistics of the statement cache in this backend.
+#[allow(dead_code)]
+pub fn statement_cache_stats() -> StatementCacheStats {
+    StatementCacheStats {
+        deparsed: STATEMENTS_DEPARSED.load(Ordering::Relaxed),
+        reused: STATEMENTS_REUSED.load(Ordering::Relaxed),
+    }
+}
+
+// how long the remote columns of a table are cached for the schema drift check
+const SCHEMA_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// remote columns of the tables checked for schema drift, keyed by connection
+// string and table, only the schemas without drift are cached
+static SCHEMA_CACHE: Mutex<BTreeMap<String, (Instant, Vec<RemoteColumn>)>> =
+    Mutex::new(BTreeMap::new());
+
+// check if a column is computed by the row callback instead of read from ClickHouse
+fn is_computed(col: &Column) -> bool {
+    col.options.get("computed") == Some(&"true".to_string())
+}
+
+// ClickHouse has no row locks, so reject row locking clauses like `FOR UPDATE`
+// instead of silently ignoring them
+#[pg_guard]
+extern "C" fn get_foreign_row_mark_type(
+    _rte: *mut pg_sys::RangeTblEntry,
+    strength: pg_sys::LockClauseStrength,
+) -> pg_sys::RowMarkType {
+    if strength != pg_sys::LockClauseStrength_LCS_NONE {
+        report_error(
+            PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+            "row locking clause, such as FOR UPDATE, is not supported on ClickHouse foreign table",
+        );
+    }
+    pg_sys::RowMarkType_ROW_MARK_COPY
+}
+
+// sequence number of queries sent to ClickHouse by this backend
+static QUERY_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// generate a query id for ClickHouse in format of
+// '<prefix>-<backend pid>-<statement start timestamp>-<sequence number>',
+// which is unique among all queries sent from a Postgres server
+fn next_query_id(prefix: &str) -> String {
+    let seq = QUERY_SEQ.fetch_add(1, Ordering::Relaxed) + 1;
+    let (pid, stmt_start) = unsafe {
+        (
+            pg_sys::MyProcPid,
+            pg_sys::GetCurrentStatementStartTimestamp(),
+        )
+    };
+    format!("{}-{}-{}-{}", prefix, pid, stmt_start, seq)
+}
+
+// create a query with query id, the query label is prepended to the sql as
+// a comment
+fn new_query(sql: &str, query_label: &Option<String>, query_id_prefix: &str) -> Query {
+    new_query_with_id(sql, query_label, &next_query_id(query_id_prefix))
+}
+
+fn new_query_with_id(sql: &str, query_label: &Option<String>, query_id: &str) -> Query {
+    let sql = match query_label {
+        Some(label) => format!("/* {} */ {}", label, sql),
+        None => sql.to_owned(),
+    };
+    Query::new(sql).id(query_id)
+}
+
+// make a text safe to be put in a SQL block comment
+fn sanitize_comment(text: &str) -> String {
+    let mut text = text.replace('\0', "");
+    while text.contains("*/") || text.contains("/*") {
+        text = text.replace("*/", "* /").replace("/*", "/ *");
+    }
+    text
+}
+
+// rows of the watermark column in (last, upper] are read by an incremental
+// scan, the values are in text form of the ClickHouse type
+struct Watermark {
+    name: String,
+    column: String,
+    last: Option<(String, String)>,
+    upper: String,
+    value_type: String,
+    // a scan with conditions doesn't read all the rows up to the upper bound,
+    // so it must not advance the watermark
+    filtered: bool,
+}
+
+impl Watermark {
+    fn conditions(&self) -> Vec<String> {
+        let literal = |value: &str, value_type: &str| {
+            format!(
+                "CAST({}, {})",
+                quote_string(value),
+                quote_string(value_type)
+            )
+        };
+        let mut conds = Vec::new();
+        if let Some((last, last_type)) = &self.last {
+            conds.push(format!("{} > {}", self.column, literal(last, last_type)));
+        }
+        conds.push(format!(
+            "{} <= {}",
+            self.column,
+            literal(&self.upper, &self.value_type)
+        ));
+        conds
     }
 }
 
@@ -79,41 +1482,1176 @@ fn field_to_cell(row: &types::Row<types::Complex>, i: usize) -> ClickHouseFdwRes
     error_type = "ClickHouseFdwError"
 )]
 pub(crate) struct ClickHouseFdw {
-    rt: Runtime,
+    // shared with the fetcher thread when block fetching is pipelined
+    rt: Arc<Runtime>,
     conn_str: String,
     client: Option<ClientHandle>,
+    // when the client connection was opened and last used
+    client_opened_at: Instant,
+    client_used_at: Instant,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive: Option<Duration>,
+    // max number of scans running concurrently on the server, and how long
+    // a scan waits for a free slot
+    max_concurrent_scans: Option<usize>,
+    acquire_timeout: Option<Duration>,
+    // session settings applied again when the connection is recycled
+    session_settings: Vec<String>,
+    // role set on the connection before scanning or writing
+    role: Option<String>,
+    // cluster the mutations run on, and how long to wait for them to finish
+    // on all the nodes
+    on_cluster: Option<String>,
+    ddl_task_timeout: Option<u64>,
     table: String,
     rowid_col: String,
     tgt_cols: Vec<Column>,
-    scan_blk: Option<Block<types::Complex>>,
+    // all the columns of the foreign table, checked against the remote table
+    // for schema drift
+    table_cols: Vec<Column>,
+    // converted cells of each target column, None for parameter column
+    scan_result: Vec<Option<Vec<Option<Cell>>>>,
+    row_cnt: usize,
+    // number of rows fetched from ClickHouse, including the rows over the limit
+    fetched_cnt: usize,
     row_idx: usize,
+    // max number of rows to read when limit is pushed down
+    scan_limit: Option<usize>,
     params: Vec<Qual>,
+    retryable_codes: Vec<u32>,
+    skip_bad_columns: bool,
+    join_use_nulls: Option<bool>,
+    max_threads: Option<u64>,
+    // queue depth of fetched blocks waiting for conversion when block fetching
+    // is pipelined with conversion on one fetcher thread, None to fetch and
+    // convert in turn
+    fetch_parallelism: Option<usize>,
+    // max number of values of a pushed down IN-list in one scan query
+    in_list_batch_size: Option<usize>,
+    // format_schema setting for Protobuf and Cap'n Proto backed tables
+    format_schema: Option<String>,
+    // inline query settings from the settings table option
+    query_settings: Vec<String>,
+    // incremental scan range of the watermark_column option
+    watermark: Option<Watermark>,
+    // SAMPLE clause of the sample table option
+    sample: Option<String>,
+    // quals not pushed down, rechecked on each row if recheck_quals is set
+    local_quals: Vec<Qual>,
+    recheck_quals: bool,
+    default_tz: Tz,
+    // datetime format settings applied to scan queries
+    datetime_settings: Vec<String>,
+    query_id_prefix: String,
+    connect_retries: u32,
+    connect_retry_backoff_ms: u64,
+    max_result_mb: Option<u64>,
+    // size of the scan result buffered locally, of all the batch queries
+    result_size: usize,
+    max_query_bytes: usize,
+    surface_warnings: bool,
+    // log ClickHouse statistics of the scan queries at the end of scan
+    log_server_stats: bool,
+    scan_query_ids: Vec<String>,
+    fetch_elapsed: Duration,
+    query_label: Option<String>,
+    // name of the SQL rewriter function in the server options, and the function
+    // resolved when a scan begins
+    sql_rewriter_name: Option<String>,
+    sql_rewriter: Option<pg_sys::Oid>,
+    // row callback function of the row_callback option, resolved when a scan
+    // begins
+    row_callback: Option<RowCallback>,
+    // widths of the target table's FixedString columns, fetched on the first
+    // inserted bytea value
+    fixed_string_widths: Option<HashMap<String, usize>>,
+    // select list of the insert_transform option and its output columns, which
+    // are validated on the first inserted row
+    insert_transform: Option<String>,
+    transform_columns: Option<Vec<String>>,
+    // if deparsed scan queries are cached for their query shapes
+    statement_cache: bool,
+    // idempotency key of the inserted rows and the number of rows inserted
+    // with it, each row gets its own deduplication token
+    dedup_token: Option<String>,
+    inserted_rows: u64,
+    // buffered rowids of deleted rows, deleted in one mutation when flushed
+    delete_rowids: Vec<Cell>,
+    batch_size: usize,
+    server_readonly: bool,
+    // name of the access token refreshed when authentication failed, and the
+    // refreshed token which replaces the password in the connection string
+    token_name: Option<String>,
+    token: Option<String>,
 }
 
-impl ClickHouseFdw {
-    const FDW_NAME: &str = "ClickHouseFdw";
+impl ClickHouseFdw {
+    pub(super) const FDW_NAME: &str = "ClickHouseFdw";
+
+    // max number of retries when scan failed with retryable error
+    const MAX_SCAN_RETRIES: u32 = 3;
+
+    // base backoff time before retrying a scan, doubled after each retry
+    const SCAN_RETRY_BACKOFF_MS: u64 = 200;
+
+    // default base backoff time before retrying a failed connection, doubled
+    // after each retry
+    const CONNECT_RETRY_BACKOFF_MS: u64 = 200;
+
+    // default retryable ClickHouse error codes, see
+    // https://github.com/ClickHouse/ClickHouse/blob/master/src/Common/ErrorCodes.cpp
+    //   202 - TOO_MANY_SIMULTANEOUS_QUERIES
+    //   241 - MEMORY_LIMIT_EXCEEDED
+    //   252 - TOO_MANY_PARTS
+    const DEFAULT_RETRYABLE_CODES: [u32; 3] = [202, 241, 252];
+
+    // ClickHouse error code of AUTHENTICATION_FAILED, the access token is
+    // refreshed and the connection is retried once on this error
+    const AUTHENTICATION_FAILED_CODE: u32 = 516;
+
+    // default max number of rows deleted in one mutation
+    const DEFAULT_BATCH_SIZE: usize = 10000;
+
+    // default time to live of the cache table in seconds
+    const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+
+    // default max length of generated scan query, same as ClickHouse's default
+    // max_query_size setting
+    const DEFAULT_MAX_QUERY_BYTES: usize = 262144;
+
+    // ClickHouse virtual column of the sampling coefficient
+    const SAMPLE_FACTOR_COLUMN: &str = "_sample_factor";
+
+    // parse comma separated error code list
+    fn parse_error_codes(codes: &str) -> ClickHouseFdwResult<Vec<u32>> {
+        codes
+            .split(',')
+            .map(|code| code.trim())
+            .filter(|code| !code.is_empty())
+            .map(|code| {
+                code.parse::<u32>().map_err(|_| {
+                    ClickHouseFdwError::InvalidOption(
+                        "retryable_error_codes".to_string(),
+                        codes.to_string(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // merge options of the connection profile referenced by the `profile` option,
+    // the given options take precedence over the profile's, and a connection
+    // string given in either form replaces the profile's in both forms
+    pub(super) fn with_profile(
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<HashMap<String, String>> {
+        match options.get("profile") {
+            Some(name) => {
+                let mut merged = profile::get_profile_options(name)
+                    .ok_or_else(|| ClickHouseFdwError::ProfileNotFound(name.to_owned()))?;
+                if options.contains_key("conn_string") || options.contains_key("conn_string_id") {
+                    merged.remove("conn_string");
+                    merged.remove("conn_string_id");
+                }
+                merged.extend(options.clone());
+                Ok(merged)
+            }
+            None => Ok(options.clone()),
+        }
+    }
+
+    fn get_conn_str(options: &HashMap<String, String>) -> ClickHouseFdwResult<String> {
+        let conn_str = match options.get("conn_string") {
+            Some(conn_str) => conn_str.to_owned(),
+            None => {
+                let conn_str_id = require_option("conn_string_id", options)?;
+                get_vault_secret(conn_str_id).unwrap_or_default()
+            }
+        };
+        Ok(conn_str)
+    }
+
+    // foreign table can override the server's connection by its own profile or
+    // connection string
+    fn override_conn_str(&mut self, options: &HashMap<String, String>) -> ClickHouseFdwResult<()> {
+        let options = Self::with_profile(options)?;
+        if options.contains_key("conn_string") || options.contains_key("conn_string_id") {
+            self.conn_str = Self::get_conn_str(&options)?;
+            self.token = None;
+        }
+        Ok(())
+    }
+
+    // connect to ClickHouse with the refreshed access token if there is one
+    fn connect(&self) -> ClickHouseFdwResult<ClientHandle> {
+        let mut options = conn_options(&self.conn_str, self.tcp_nodelay, self.tcp_keepalive)?;
+        if let Some(token) = &self.token {
+            options = options.password(token);
+        }
+        let pool = Pool::new(options);
+        connect_with_retry(self.connect_retries, self.connect_retry_backoff_ms, || {
+            self.rt.block_on(pool.get_handle())
+        })
+    }
+
+    fn create_client(&mut self) -> ClickHouseFdwResult<()> {
+        let client = match self.connect() {
+            Err(ClickHouseFdwError::ClickHouseError(clickhouse_rs::errors::Error::Server(err)))
+                if err.code == Self::AUTHENTICATION_FAILED_CODE && self.token_name.is_some() =>
+            {
+                let token_name = self.token_name.as_deref().unwrap_or_default();
+                match auth::refresh_token(Self::FDW_NAME, token_name) {
+                    Some(token) => {
+                        log_debug1(&format!("access token '{}' refreshed", token_name));
+                        self.token = Some(token);
+                        self.connect()?
+                    }
+                    None => return Err(clickhouse_rs::errors::Error::Server(err).into()),
+                }
+            }
+            result => result?,
+        };
+        metrics::inc(Self::FDW_NAME, Metric::ConnectionsOpened, 1);
+        self.client = Some(client);
+        self.client_opened_at = Instant::now();
+        self.client_used_at = self.client_opened_at;
+        if let Some(role) = self.role.clone() {
+            self.execute_on_client(&format!("set role {}", quote_identifier(&role)))?;
+        }
+        Ok(())
+    }
+
+    // compare the remote table's columns with the foreign table's columns, drift
+    // is reported as an error or a warning depending on the on_schema_drift option
+    fn check_schema_drift(&mut self, options: &HashMap<String, String>) -> ClickHouseFdwResult<()> {
+        let policy = match options.get("on_schema_drift").map(|v| v.as_str()) {
+            None | Some("ignore") => return Ok(()),
+            Some(policy @ ("error" | "warn")) => policy,
+            Some(v) => {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "on_schema_drift".to_string(),
+                    v.to_string(),
+                ))
+            }
+        };
+        // parameterized subquery cannot be described without its parameters
+        if self.table.contains("${") {
+            return Ok(());
+        }
+
+        let key = format!("{}|{}", self.conn_str, self.table);
+        let cached = SCHEMA_CACHE
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < SCHEMA_CACHE_TTL)
+            .map(|(_, remote_cols)| remote_cols.clone());
+        let remote_cols = match cached {
+            Some(remote_cols) => remote_cols,
+            None => self.fetch_columns(&self.table.clone())?,
+        };
+        let columns = self
+            .table_cols
+            .iter()
+            .filter(|c| !is_computed(c) && c.name != Self::SAMPLE_FACTOR_COLUMN)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut cache = SCHEMA_CACHE.lock().unwrap();
+        match schema_drift(&columns, &remote_cols) {
+            None => {
+                cache.insert(key, (Instant::now(), remote_cols));
+                Ok(())
+            }
+            Some(drift) => {
+                // drifted schema is fetched again, so a fix is seen at once
+                cache.remove(&key);
+                let err = ClickHouseFdwError::SchemaDrift(self.table.clone(), drift);
+                if policy == "error" {
+                    return Err(err);
+                }
+                report_warning(&err.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // take a slot of the server's concurrent scans, waiting up to
+    // acquire_timeout for a free one
+    fn acquire_scan_slot(&self) -> ClickHouseFdwResult<Option<Permit>> {
+        let max = match self.max_concurrent_scans {
+            Some(max) => max,
+            None => return Ok(None),
+        };
+        semaphore::acquire(
+            &scan_semaphore_name(&self.conn_str),
+            max,
+            self.acquire_timeout,
+        )
+        .map(Some)
+        .ok_or(ClickHouseFdwError::ScanSlotTimeout(max))
+    }
+
+    // execute a statement on the current connection
+    fn execute_on_client(&mut self, sql: &str) -> ClickHouseFdwResult<()> {
+        if let Some(ref mut client) = self.client {
+            let query = new_query(sql, &self.query_label, &self.query_id_prefix);
+            self.rt.block_on(client.execute(query))?;
+        }
+        Ok(())
+    }
+
+    // close the connection, connections are not reused so the role set on it
+    // goes away with it
+    fn release_client(&mut self) {
+        self.client = None;
+    }
+
+    // apply the session settings on the current connection
+    fn apply_session_settings(&mut self) -> ClickHouseFdwResult<()> {
+        if self.session_settings.is_empty() {
+            return Ok(());
+        }
+        self.execute_on_client(&format!("set {}", self.session_settings.join(", ")))
+    }
+
+    // reconnect if the connection has been idle longer than idle_timeout or
+    // opened longer than max_lifetime, so a long running modify doesn't fail on
+    // a connection silently dropped by network middleboxes
+    fn recycle_client(&mut self) -> ClickHouseFdwResult<()> {
+        if self.client.is_none() {
+            return Ok(());
+        }
+        let idle_expired = self
+            .idle_timeout
+            .map(|timeout| self.client_used_at.elapsed() >= timeout)
+            .unwrap_or(false);
+        let lifetime_expired = self
+            .max_lifetime
+            .map(|lifetime| self.client_opened_at.elapsed() >= lifetime)
+            .unwrap_or(false);
+        if idle_expired || lifetime_expired {
+            log_debug1("recycle ClickHouse connection");
+            self.client = None;
+            self.create_client()?;
+            self.apply_session_settings()?;
+        }
+        self.client_used_at = Instant::now();
+        Ok(())
+    }
+
+    // parse datetime format settings in the server options, only the values
+    // known by ClickHouse are accepted
+    fn parse_datetime_settings(
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<Vec<String>> {
+        let known_values: [(&str, &[&str]); 2] = [
+            (
+                "date_time_output_format",
+                &["simple", "iso", "unix_timestamp"],
+            ),
+            (
+                "date_time_input_format",
+                &["basic", "best_effort", "best_effort_us"],
+            ),
+        ];
+        let mut settings = Vec::new();
+        for (name, values) in known_values {
+            if let Some(v) = options.get(name) {
+                if !values.contains(&v.as_str()) {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        name.to_string(),
+                        v.to_owned(),
+                    ));
+                }
+                settings.push(format!("{} = '{}'", name, v));
+            }
+        }
+        Ok(settings)
+    }
+
+    // parse insert durability settings in the table options
+    fn parse_insert_settings(
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<Vec<String>> {
+        let mut settings = Vec::new();
+        if let Some(v) = options.get("insert_quorum") {
+            if v == "auto" {
+                settings.push("insert_quorum = 'auto'".to_string());
+            } else {
+                let quorum: u64 = Self::parse_num_option(options, "insert_quorum", 0)?;
+                settings.push(format!("insert_quorum = {}", quorum));
+            }
+        }
+        if options.contains_key("insert_quorum_timeout") {
+            let timeout: u64 = Self::parse_num_option(options, "insert_quorum_timeout", 0)?;
+            settings.push(format!("insert_quorum_timeout = {}", timeout));
+        }
+        if let Some(v) = options.get("insert_deduplicate") {
+            let dedup = match v.as_str() {
+                "true" => 1,
+                "false" => 0,
+                _ => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "insert_deduplicate".to_string(),
+                        v.to_owned(),
+                    ))
+                }
+            };
+            settings.push(format!("insert_deduplicate = {}", dedup));
+        }
+        if let Some(v) = options.get("input_format_settings") {
+            // only the settings controlling how inserted data is parsed are
+            // accepted, other settings would leak into the whole modify
+            let input_settings = parse_query_settings("input_format_settings", v)?;
+            if input_settings
+                .iter()
+                .any(|setting| !setting.starts_with("input_format_"))
+            {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "input_format_settings".to_string(),
+                    v.to_owned(),
+                ));
+            }
+            settings.extend(input_settings);
+        }
+        Ok(settings)
+    }
+
+    // parse the format_schema table option, it must be `<schema_file>:<message>`
+    // with the schema file relative to ClickHouse's format_schema_path
+    fn parse_format_schema(
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<Option<String>> {
+        let Some(v) = options.get("format_schema") else {
+            return Ok(None);
+        };
+        let invalid =
+            || ClickHouseFdwError::InvalidOption("format_schema".to_string(), v.to_owned());
+        let (file, message) = v.rsplit_once(':').ok_or_else(invalid)?;
+        let valid_file = !file.is_empty()
+            && !file.starts_with('/')
+            && file.split('/').all(|part| !part.is_empty() && part != "..");
+        let valid_message = message.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+        if !valid_file || !valid_message {
+            return Err(invalid());
+        }
+        Ok(Some(format!(
+            "format_schema = '{}'",
+            v.replace('\\', "\\\\").replace('\'', "\\'")
+        )))
+    }
+
+    // check if the row satisfies all the quals not pushed down
+    fn satisfies_local_quals(&self, row: &Row) -> bool {
+        // a qual on a column not in the row cannot be evaluated
+        self.local_quals
+            .iter()
+            .all(|qual| match row.get_by_name(&qual.field) {
+                Some(cell) => qual.apply(cell.as_ref()) != Some(false),
+                None => true,
+            })
+    }
+
+    // check if the target column is fetched from ClickHouse, parameter columns
+    // are filled from the quals and computed columns by the row callback
+    fn is_fetched(&self, col: &Column) -> bool {
+        !is_computed(col) && !self.params.iter().any(|p| p.field == col.name)
+    }
+
+    // delete all buffered rows in one mutation
+    fn flush_deletes(&mut self) -> ClickHouseFdwResult<()> {
+        if self.delete_rowids.is_empty() {
+            return Ok(());
+        }
+        self.recycle_client()?;
+        let rowids = std::mem::take(&mut self.delete_rowids);
+        if let Some(ref mut client) = self.client {
+            let values = rowids
+                .iter()
+                .map(|rowid| {
+                    typed_literal(rowid, self.default_tz).unwrap_or_else(|| rowid.to_string())
+                })
+                .collect::<Vec<_>>();
+            let sql = alter_table_sql(
+                &self.table,
+                self.on_cluster.as_deref(),
+                self.ddl_task_timeout,
+                &format!("delete where {} in ({})", self.rowid_col, values.join(", ")),
+            );
+
+            // execute query on ClickHouse
+            let query = new_query(&sql, &self.query_label, &self.query_id_prefix);
+            self.rt.block_on(client.execute(query))?;
+        }
+        Ok(())
+    }
+
+    // parse a non-negative integer option
+    fn parse_num_option<T: FromStr>(
+        options: &HashMap<String, String>,
+        name: &str,
+        default: T,
+    ) -> ClickHouseFdwResult<T> {
+        match options.get(name) {
+            Some(v) => v
+                .parse::<T>()
+                .map_err(|_| ClickHouseFdwError::InvalidOption(name.to_string(), v.to_owned())),
+            None => Ok(default),
+        }
+    }
+
+    // parse the distributed_ddl_task_timeout option in seconds, ClickHouse's
+    // own default is used if it is not set
+    fn parse_ddl_task_timeout(
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<Option<u64>> {
+        match options.get("distributed_ddl_task_timeout") {
+            None => Ok(None),
+            Some(v) => match v.parse::<u64>() {
+                Ok(secs) if secs > 0 => Ok(Some(secs)),
+                _ => Err(ClickHouseFdwError::InvalidOption(
+                    "distributed_ddl_task_timeout".to_string(),
+                    v.to_owned(),
+                )),
+            },
+        }
+    }
+
+    // parse the sample option, which is a ratio or a row count with an optional
+    // offset ratio, such as '0.1', '1/10', '100000' or '1/10 offset 1/2',
+    // sampling only works on a table but not a subquery
+    fn parse_sample_option(
+        &self,
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<Option<String>> {
+        let sample = match options.get("sample") {
+            Some(sample) => sample,
+            None => return Ok(None),
+        };
+        let re = Regex::new(
+            r"(?i)^\s*([0-9]+(\.[0-9]+)?(/[0-9]+)?)(\s+offset\s+([0-9]+(\.[0-9]+)?(/[0-9]+)?))?\s*$",
+        )
+        .unwrap();
+        match re.captures(sample) {
+            Some(caps) if !self.table.starts_with('(') => Ok(Some(match caps.get(5) {
+                Some(offset) => format!("{} offset {}", &caps[1], offset.as_str()),
+                None => caps[1].to_string(),
+            })),
+            _ => Err(ClickHouseFdwError::InvalidOption(
+                "sample".to_string(),
+                sample.to_owned(),
+            )),
+        }
+    }
+
+    // parse the cache_table and cache_ttl options, the cache table must exist
+    // and the source cannot be a parameterized subquery
+    fn parse_cache_options(
+        &self,
+        options: &HashMap<String, String>,
+    ) -> ClickHouseFdwResult<Option<(String, u64)>> {
+        let name = match options.get("cache_table") {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if self.table.contains("${") {
+            return Err(ClickHouseFdwError::InvalidOption(
+                "cache_table".to_string(),
+                name.to_owned(),
+            ));
+        }
+        let cache_table = cache::resolve_cache_table(name)
+            .ok_or_else(|| ClickHouseFdwError::CacheTableNotFound(name.to_owned()))?;
+        let ttl = Self::parse_num_option(options, "cache_ttl", Self::DEFAULT_CACHE_TTL_SECS)?;
+        Ok(Some((cache_table, ttl)))
+    }
+
+    // read all rows of the source into the cache table, the cache table's
+    // columns are read with the options of the same named foreign table columns
+    fn refresh_cache(&mut self, cache_table: &str, columns: &[Column]) -> ClickHouseFdwResult<()> {
+        let cache_cols = cache::get_cache_columns(cache_table);
+        self.tgt_cols = cache_cols
+            .iter()
+            .enumerate()
+            .map(|(i, (name, type_oid))| Column {
+                name: name.to_owned(),
+                num: i + 1,
+                type_oid: *type_oid,
+                options: columns
+                    .iter()
+                    .find(|c| &c.name == name)
+                    .map(|c| c.options.clone())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        let tgt_cols = self.tgt_cols.clone();
+        let sql = self.deparse(&[], &tgt_cols, &[], &None, true)?;
+        self.query_batches(&[sql])?;
+
+        let mut scan_result = mem::take(&mut self.scan_result);
+        let rows = (0..self.row_cnt).map(|row_idx| {
+            scan_result
+                .iter_mut()
+                .map(|cells| cells.as_mut().and_then(|cells| cells[row_idx].take()))
+                .collect()
+        });
+        cache::refresh_cache(cache_table, &cache_cols, rows);
+        self.row_cnt = 0;
+        self.result_size = 0;
+        Ok(())
+    }
+
+    // read the scan result from the cache table, all the quals, sorts and
+    // limit are applied by Postgres
+    fn read_cache(
+        &mut self,
+        cache_table: &str,
+        quals: &[Qual],
+        columns: &[Column],
+    ) -> ClickHouseFdwResult<()> {
+        let cache_cols = cache::get_cache_columns(cache_table);
+        let fetched_cols = columns
+            .iter()
+            .filter(|c| !is_computed(c))
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        if let Some(col) = fetched_cols
+            .iter()
+            .find(|&&col| !cache_cols.iter().any(|(name, _)| name == col))
+        {
+            return Err(ClickHouseFdwError::CacheColumnNotFound(
+                col.to_string(),
+                cache_table.to_owned(),
+            ));
+        }
+
+        let (mut cells, row_cnt) = cache::read_cache(cache_table, &fetched_cols);
+        cells.reverse();
+        self.tgt_cols = columns.to_vec();
+        self.scan_result = columns
+            .iter()
+            .map(|c| if is_computed(c) { None } else { cells.pop() })
+            .collect();
+        self.row_cnt = row_cnt;
+        self.row_idx = 0;
+        self.scan_limit = None;
+        self.params.clear();
+        self.local_quals = quals.to_vec();
+        Ok(())
+    }
+
+    // convert the block to cells column by column, so each column's type is only
+    // checked once instead of for every cell
+    fn convert_block(
+        &self,
+        block: &Block,
+        row_offset: usize,
+    ) -> ClickHouseFdwResult<Vec<Option<Vec<Option<Cell>>>>> {
+        let mut result = Vec::with_capacity(self.tgt_cols.len());
+        for tgt_col in &self.tgt_cols {
+            if !self.is_fetched(tgt_col) {
+                result.push(None);
+                continue;
+            }
+
+            // result columns are matched by name, so extra columns returned
+            // by a subquery or rewritten SQL are ignored
+            let col = block
+                .columns()
+                .iter()
+                .position(|c| c.name() == tgt_col.name)
+                .ok_or_else(|| ClickHouseFdwError::ColumnNotFound(tgt_col.name.to_owned()))?;
+            let int8_as_bool = is_int8_as_bool(tgt_col);
+            let composite_fields = composite_type_fields(tgt_col.type_oid);
+            if int8_as_bool {
+                let sql_type = block.columns()[col].sql_type();
+                if !matches!(sql_type, SqlType::Int8)
+                    && !matches!(sql_type, SqlType::Nullable(SqlType::Int8))
+                {
+                    return Err(ClickHouseFdwError::UnsupportedColumnType(format!(
+                        "{} with int8_as_bool",
+                        sql_type
+                    )));
+                }
+            }
+            let cells = if is_decimal_as_float(tgt_col) {
+                decimal_column_to_floats(block, col)?
+            } else if is_subsecond_round(tgt_col) {
+                datetime_column_to_rounded_timestamps(block, col)
+            } else {
+                column_to_cells(block, col)
+            };
+            let cells = cells
+                .into_iter()
+                .map(|cell| match cell {
+                    // Int8 is read as smallint, turn it into bool
+                    Ok(Some(Cell::I16(v))) if int8_as_bool => Ok(Some(Cell::Bool(v != 0))),
+                    Ok(Some(Cell::String(s))) if composite_fields.is_some() => {
+                        let fields = composite_fields.as_deref().unwrap_or_default();
+                        tuple_to_composite(&s, tgt_col, fields).map(Some)
+                    }
+                    cell => cell,
+                })
+                .enumerate()
+                .map(|(row_idx, cell)| {
+                    match cell.and_then(|cell| convert_cell(cell, tgt_col, self.default_tz)) {
+                        Ok(cell) => Ok(cell),
+                        Err(err) if self.skip_bad_columns => {
+                            report_warning(&format!(
+                                "column '{}' in row {} is set to null: {}",
+                                tgt_col.name,
+                                row_offset + row_idx,
+                                err
+                            ));
+                            Ok(None)
+                        }
+                        Err(err) => Err(err),
+                    }
+                })
+                .collect::<ClickHouseFdwResult<Vec<Option<Cell>>>>()?;
+            result.push(Some(cells));
+        }
+        Ok(result)
+    }
+
+    fn replace_all_params(
+        &mut self,
+        re: &Regex,
+        mut replacement: impl FnMut(&Captures) -> ClickHouseFdwResult<String>,
+    ) -> ClickHouseFdwResult<String> {
+        let mut new = String::with_capacity(self.table.len());
+        let mut last_match = 0;
+        for caps in re.captures_iter(&self.table) {
+            let m = caps.get(0).unwrap();
+            new.push_str(&self.table[last_match..m.start()]);
+            new.push_str(&replacement(&caps)?);
+            last_match = m.end();
+        }
+        new.push_str(&self.table[last_match..]);
+        Ok(new)
+    }
+
+    // run a query and fetch all the result, the query is retried with backoff
+    // if it failed with a retryable server error
+    fn query_with_retry(&mut self, sql: &str) -> ClickHouseFdwResult<()> {
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        let mut retries = 0;
+        // the result of a failed attempt is discarded, so is its size
+        let result_size = self.result_size;
+        let result = loop {
+            self.result_size = result_size;
+            match self.fetch_blocks(&mut client, sql) {
+                Ok(()) => break Ok(()),
+                Err(ClickHouseFdwError::ClickHouseError(clickhouse_rs::errors::Error::Server(
+                    err,
+                ))) if retries < Self::MAX_SCAN_RETRIES
+                    && self.retryable_codes.contains(&err.code) =>
+                {
+                    let backoff = Self::SCAN_RETRY_BACKOFF_MS * 2u64.pow(retries);
+                    retries += 1;
+                    log_debug1(&format!(
+                        "retry scan ({}/{}) in {}ms: {}",
+                        retries,
+                        Self::MAX_SCAN_RETRIES,
+                        backoff,
+                        err
+                    ));
+                    thread::sleep(Duration::from_millis(backoff));
+                    pgrx::check_for_interrupts!();
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        self.client = Some(client);
+        result
+    }
+
+    // run the batch queries one by one and concatenate their results
+    fn query_batches(&mut self, sqls: &[String]) -> ClickHouseFdwResult<()> {
+        let mut scan_result: Vec<Option<Vec<Option<Cell>>>> = Vec::new();
+        let mut row_cnt = 0;
+        let mut fetched_cnt = 0;
+        for (i, sql) in sqls.iter().enumerate() {
+            self.query_with_retry(&rewrite_sql(self.sql_rewriter, sql))?;
+            if i == 0 {
+                scan_result = mem::take(&mut self.scan_result);
+            } else {
+                for (result, cells) in scan_result.iter_mut().zip(self.scan_result.drain(..)) {
+                    if let (Some(result), Some(cells)) = (result, cells) {
+                        result.extend(cells);
+                    }
+                }
+            }
+            row_cnt += self.row_cnt;
+            fetched_cnt += self.fetched_cnt;
+        }
+        self.scan_result = scan_result;
+        self.row_cnt = row_cnt;
+
+        // the rows are counted once all the batches are fetched, so rows of
+        // failed attempts are not counted
+        stats::inc_stats(Self::FDW_NAME, stats::Metric::RowsIn, fetched_cnt as i64);
+        stats::inc_stats(Self::FDW_NAME, stats::Metric::RowsOut, row_cnt as i64);
+        Ok(())
+    }
+
+    // check if the connection is restricted by the 'readonly' setting, which
+    // can be set in the connection string or by the user's settings profile
+    fn is_readonly_connection(&mut self) -> ClickHouseFdwResult<bool> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(false),
+        };
+        let query = new_query(
+            "select toUInt64(getSetting('readonly')) as readonly",
+            &self.query_label,
+            &self.query_id_prefix,
+        );
+        let block = self.rt.block_on(client.query(query).fetch_all())?;
+        Ok(block.get::<u64, _>(0, "readonly")? != 0)
+    }
+
+    // get the max value of the watermark column of rows newer than the last
+    // watermark with its type name, return None if there is no newer row
+    fn fetch_watermark_upper(
+        &mut self,
+        column: &str,
+        last: &Option<(String, String)>,
+    ) -> ClickHouseFdwResult<Option<(String, String)>> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(None),
+        };
+        let mut sql = format!(
+            "select count({0}) as cnt, toString(assumeNotNull(max({0}))) as value, \
+             toTypeName(assumeNotNull(max({0}))) as value_type from {1}",
+            column, self.table
+        );
+        if let Some((last, last_type)) = last {
+            sql.push_str(&format!(
+                " where {} > CAST({}, {})",
+                column,
+                quote_string(last),
+                quote_string(last_type)
+            ));
+        }
+        let query = new_query(&sql, &self.query_label, &self.query_id_prefix);
+        let block = self.rt.block_on(client.query(query).fetch_all())?;
+        if block.get::<u64, _>(0, "cnt")? == 0 {
+            return Ok(None);
+        }
+        Ok(Some((
+            block.get::<String, _>(0, "value")?,
+            block.get::<String, _>(0, "value_type")?,
+        )))
+    }
+
+    // get name, type, nullability and default kind of the remote table's
+    // columns in their defined order
+    fn fetch_columns(&mut self, table: &str) -> ClickHouseFdwResult<Vec<RemoteColumn>> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(Vec::new()),
+        };
+        let query = new_query(
+            &format!("describe table {}", table),
+            &self.query_label,
+            &self.query_id_prefix,
+        );
+        let block = self.rt.block_on(client.query(query).fetch_all())?;
+        (0..block.row_count())
+            .map(|row| {
+                let sql_type = block.get::<String, _>(row, "type")?;
+                let default_kind = block.get::<String, _>(row, "default_type")?;
+                Ok((
+                    block.get::<String, _>(row, "name")?,
+                    sql_type.clone(),
+                    is_nullable_type(&sql_type),
+                    (!default_kind.is_empty()).then_some(default_kind),
+                ))
+            })
+            .collect()
+    }
+
+    // insert a row through the insert_transform select list, which is evaluated
+    // by ClickHouse on the row's values, so derived columns are computed on
+    // write
+    fn insert_transformed(&mut self, transform: &str, src: &Row) -> ClickHouseFdwResult<()> {
+        let source = src
+            .iter()
+            .map(|(col, cell)| {
+                format!(
+                    "{} as {}",
+                    insert_literal(cell, self.default_tz),
+                    quote_identifier(col)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let select = format!("select {} from (select {})", transform, source);
+
+        // the output columns must exist in the target table, they are inserted
+        // by name rather than by position
+        if self.transform_columns.is_none() {
+            let table = self.table.clone();
+            let table_cols = self.fetch_columns(&table)?;
+            let output_cols = self
+                .fetch_columns(&format!("({})", select))?
+                .into_iter()
+                .map(|(name, ..)| name)
+                .collect::<Vec<_>>();
+            if let Some(col) = output_cols
+                .iter()
+                .find(|col| !table_cols.iter().any(|(name, ..)| name == *col))
+            {
+                return Err(ClickHouseFdwError::InsertTransformColumnNotFound(
+                    col.to_owned(),
+                ));
+            }
+            self.transform_columns = Some(output_cols);
+        }
+        let cols = self
+            .transform_columns
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(|col| quote_identifier(col))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("insert into {} ({}) {}", self.table, cols, select);
+
+        if let Some(ref mut client) = self.client {
+            let query = new_query(&sql, &self.query_label, &self.query_id_prefix);
+            self.rt.block_on(client.execute(query))?;
+        }
+        Ok(())
+    }
+
+    // get the statistics of the scan queries from ClickHouse query log, which
+    // is flushed first as the native protocol's progress packets are not
+    // exposed by the driver
+    fn fetch_scan_stats(&mut self) -> ClickHouseFdwResult<ScanStats> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(ScanStats::default()),
+        };
+        let mut stats = ScanStats {
+            fetch_ms: self.fetch_elapsed.as_millis() as u64,
+            ..Default::default()
+        };
+        if self.scan_query_ids.is_empty() {
+            return Ok(stats);
+        }
+        let query = new_query(
+            "system flush logs",
+            &self.query_label,
+            &self.query_id_prefix,
+        );
+        self.rt.block_on(client.execute(query))?;
+        let sql = format!(
+            "select count() as queries, sum(read_rows) as read_rows, \
+             sum(read_bytes) as read_bytes, sum(query_duration_ms) as server_ms \
+             from system.query_log where type = 'QueryFinish' and query_id in ({})",
+            self.scan_query_ids
+                .iter()
+                .map(|id| quote_string(id))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let query = new_query(&sql, &self.query_label, &self.query_id_prefix);
+        let block = self.rt.block_on(client.query(query).fetch_all())?;
+        stats.queries = block.get::<u64, _>(0, "queries")?;
+        stats.read_rows = block.get::<u64, _>(0, "read_rows")?;
+        stats.read_bytes = block.get::<u64, _>(0, "read_bytes")?;
+        stats.server_ms = block.get::<u64, _>(0, "server_ms")?;
+        Ok(stats)
+    }
+
+    // get the server warnings, such as deprecated settings are used, the native
+    // protocol doesn't send warnings with query result so they are read from
+    // the system.warnings table
+    fn server_warnings(&mut self) -> ClickHouseFdwResult<Vec<String>> {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return Ok(Vec::new()),
+        };
+        let query = new_query(
+            "select message from system.warnings",
+            &self.query_label,
+            &self.query_id_prefix,
+        );
+        let block = self.rt.block_on(client.query(query).fetch_all())?;
+        (0..block.row_count())
+            .map(|row| Ok(block.get::<String, _>(row, "message")?))
+            .collect()
+    }
+
+    // fetch the query result block by block and convert them to cells
+    fn fetch_blocks(&mut self, client: &mut ClientHandle, sql: &str) -> ClickHouseFdwResult<()> {
+        self.scan_result = self
+            .tgt_cols
+            .iter()
+            .map(|c| {
+                if !self.is_fetched(c) {
+                    None
+                } else {
+                    Some(Vec::new())
+                }
+            })
+            .collect();
+        self.row_cnt = 0;
+        self.fetched_cnt = 0;
+
+        let query_id = next_query_id(&self.query_id_prefix);
+        let query = new_query_with_id(sql, &self.query_label, &query_id);
+        if self.log_server_stats {
+            self.scan_query_ids.push(query_id);
+        }
+        let mut stream = client.query(query).stream_blocks();
+        match self.fetch_parallelism {
+            None => {
+                while let Some(block) = self.rt.block_on(stream.next()) {
+                    self.add_block(&block?)?;
+                }
+                Ok(())
+            }
+            // the blocks are fetched on another thread while the fetched ones
+            // are converted here, at most `depth` blocks are buffered in
+            // between so a slow conversion holds back the fetching
+            Some(depth) => {
+                let rt = self.rt.clone();
+                thread::scope(|scope| {
+                    let (tx, rx) = mpsc::sync_channel(depth);
+                    // dropped when the conversion stops, which stops the fetching
+                    let (_stop, stopped) = oneshot::channel::<()>();
+                    scope.spawn(move || {
+                        rt.block_on(async move {
+                            let mut stopped = stopped;
+                            while let Either::Left((Some(block), _)) =
+                                future::select(stream.next(), &mut stopped).await
+                            {
+                                if tx.send(block).is_err() {
+                                    break;
+                                }
+                            }
+                        })
+                    });
+                    for block in rx {
+                        self.add_block(&block?)?;
+                    }
+                    Ok(())
+                })
+            }
+        }
+    }
 
-    fn create_client(&mut self) -> ClickHouseFdwResult<()> {
-        let pool = Pool::new(self.conn_str.as_str());
-        self.client = Some(self.rt.block_on(pool.get_handle())?);
+    // convert a fetched block and append its cells to the scan result, the
+    // locally buffered result size is checked against max_result_mb after
+    // each block
+    fn add_block(&mut self, block: &Block) -> ClickHouseFdwResult<()> {
+        self.fetched_cnt += block.row_count();
+
+        // stop converting once the pushed down limit is reached, the rest
+        // of the stream is still drained so the connection can be reused
+        let row_cnt = match self.scan_limit {
+            Some(limit) => block.row_count().min(limit.saturating_sub(self.row_cnt)),
+            None => block.row_count(),
+        };
+        if row_cnt == 0 {
+            return Ok(());
+        }
+
+        let cells = self
+            .convert_block(block, self.row_cnt)?
+            .into_iter()
+            .map(|cells| {
+                cells.map(|mut cells| {
+                    cells.truncate(row_cnt);
+                    cells
+                })
+            })
+            .collect::<Vec<_>>();
+        if let Some(max_result_mb) = self.max_result_mb {
+            self.result_size += cells
+                .iter()
+                .flatten()
+                .flatten()
+                .map(cell_size)
+                .sum::<usize>();
+            if self.result_size > max_result_mb as usize * 1024 * 1024 {
+                return Err(ClickHouseFdwError::ResultTooLarge(max_result_mb));
+            }
+        }
+        for (result, cells) in self.scan_result.iter_mut().zip(cells) {
+            if let (Some(result), Some(cells)) = (result, cells) {
+                result.extend(cells);
+            }
+        }
+        self.row_cnt += row_cnt;
+
+        pgrx::check_for_interrupts!();
         Ok(())
     }
 
-    fn replace_all_params(
+    // deparse the scan queries, if in_list_batch_size is set the longest
+    // pushed down IN-list exceeding it is split into batches, one query per
+    // batch, so a large semi-join doesn't make a single huge query
+    fn deparse_batches(
         &mut self,
-        re: &Regex,
-        mut replacement: impl FnMut(&Captures) -> ClickHouseFdwResult<String>,
-    ) -> ClickHouseFdwResult<String> {
-        let mut new = String::with_capacity(self.table.len());
-        let mut last_match = 0;
-        for caps in re.captures_iter(&self.table) {
-            let m = caps.get(0).unwrap();
-            new.push_str(&self.table[last_match..m.start()]);
-            new.push_str(&replacement(&caps)?);
-            last_match = m.end();
-        }
-        new.push_str(&self.table[last_match..]);
-        Ok(new)
+        quals: &[Qual],
+        columns: &[Column],
+        sorts: &[Sort],
+        limit: &Option<Limit>,
+    ) -> ClickHouseFdwResult<Vec<String>> {
+        let batch = self.in_list_batch_size.and_then(|batch_size| {
+            quals
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, q)| match &q.value {
+                    Value::Array(arr)
+                        if q.operator == "="
+                            && q.use_or
+                            && arr.len() > batch_size
+                            && !columns
+                                .iter()
+                                .any(|c| c.name == q.field && is_local_column(c)) =>
+                    {
+                        Some((idx, arr))
+                    }
+                    _ => None,
+                })
+                .max_by_key(|(_, arr)| arr.len())
+                .map(|(idx, arr)| (idx, arr, batch_size))
+        });
+        let (idx, arr, batch_size) = match batch {
+            Some(batch) => batch,
+            None => return Ok(vec![self.deparse(quals, columns, sorts, limit, true)?]),
+        };
+
+        // duplicated values are removed, so no row is returned by two batches
+        let mut seen = HashSet::new();
+        let values = arr
+            .iter()
+            .filter(|cell| seen.insert(cell.to_string()))
+            .cloned()
+            .collect::<Vec<Cell>>();
+
+        let mut batch_quals = quals.to_vec();
+        values
+            .chunks(batch_size)
+            .map(|chunk| {
+                batch_quals[idx].value = Value::Array(chunk.to_vec());
+                self.deparse(&batch_quals, columns, sorts, limit, true)
+            })
+            .collect()
     }
 
     fn deparse(
@@ -122,6 +2660,7 @@ impl ClickHouseFdw {
         columns: &[Column],
         sorts: &[Sort],
         limit: &Option<Limit>,
+        push_array_quals: bool,
     ) -> ClickHouseFdwResult<String> {
         let table = if self.table.starts_with('(') {
             let re = Regex::new(r"\$\{(\w+)\}").unwrap();
@@ -130,9 +2669,23 @@ impl ClickHouseFdw {
                 let param = &caps[1];
                 for qual in quals.iter() {
                     if qual.field == param {
-                        params.push(qual.clone());
                         match &qual.value {
-                            Value::Cell(cell) => return Ok(cell.to_string()),
+                            Value::Cell(cell) => {
+                                params.push(qual.clone());
+                                return Ok(cell.to_string());
+                            }
+                            // `param in (...)` is replaced with a value list, which
+                            // can be used as `col in ${param}` in the subquery, the
+                            // param column has multiple values so it is not filled
+                            // locally but must be returned by the subquery
+                            Value::Array(arr) if qual.operator == "=" && qual.use_or => {
+                                let values = arr
+                                    .iter()
+                                    .map(|cell| cell.to_string())
+                                    .collect::<Vec<String>>()
+                                    .join(", ");
+                                return Ok(format!("({})", values));
+                            }
                             Value::Array(arr) => {
                                 return Err(ClickHouseFdwError::NoArrayParameter(format!(
                                     "{:?}",
@@ -146,86 +2699,556 @@ impl ClickHouseFdw {
             };
             let s = self.replace_all_params(&re, &mut replacement)?;
             self.params = params;
-            s
+            strip_totals(&s)
         } else {
             self.table.clone()
         };
 
-        let tgts = if columns.is_empty() {
+        // subqueries with parameters have the parameter values in the table, so
+        // they are not cached
+        let key = (self.statement_cache && !self.table.contains("${"))
+            .then(|| self.statement_key(&table, quals, columns, sorts, limit, push_array_quals));
+        let cached = key
+            .as_ref()
+            .and_then(|key| STATEMENT_CACHE.lock().unwrap().get(key).cloned());
+        let stmt = match cached {
+            Some(stmt) => {
+                STATEMENTS_REUSED.fetch_add(1, Ordering::Relaxed);
+                stmt
+            }
+            None => {
+                STATEMENTS_DEPARSED.fetch_add(1, Ordering::Relaxed);
+                let stmt =
+                    self.deparse_statement(&table, quals, columns, sorts, limit, push_array_quals);
+                if let Some(key) = key {
+                    let mut cache = STATEMENT_CACHE.lock().unwrap();
+                    if cache.len() >= STATEMENT_CACHE_SIZE {
+                        cache.clear();
+                    }
+                    cache.insert(key, stmt.clone());
+                }
+                stmt
+            }
+        };
+
+        Ok(self.fill_statement(&stmt, quals, columns, limit))
+    }
+
+    // key of a scan query's shape, which has everything the deparsed statement
+    // depends on except the literals, so scans with different options never
+    // share a statement
+    fn statement_key(
+        &self,
+        table: &str,
+        quals: &[Qual],
+        columns: &[Column],
+        sorts: &[Sort],
+        limit: &Option<Limit>,
+        push_array_quals: bool,
+    ) -> String {
+        let cols = columns
+            .iter()
+            .map(|c| {
+                let opts = c.options.iter().collect::<BTreeMap<_, _>>();
+                format!("{}:{}:{:?}", c.name, c.type_oid.as_u32(), opts)
+            })
+            .collect::<Vec<_>>();
+        let quals = quals
+            .iter()
+            .map(|q| {
+                let is_array = matches!(q.value, Value::Array(_));
+                format!("{} {} {} {}", q.field, q.operator, q.use_or, is_array)
+            })
+            .collect::<Vec<_>>();
+        let sorts = sorts
+            .iter()
+            .map(|sort| format!("{} {:?}", sort.deparse(), sort.collate))
+            .collect::<Vec<_>>();
+        format!(
+            "{}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{}|{}",
+            table,
+            self.sample,
+            self.join_use_nulls,
+            self.max_threads,
+            self.format_schema,
+            self.datetime_settings,
+            self.query_settings,
+            self.params.iter().map(|p| &p.field).collect::<Vec<_>>(),
+            cols,
+            quals,
+            sorts,
+            limit.is_some(),
+            push_array_quals,
+        )
+    }
+
+    // deparse the statement of a scan query, which doesn't depend on the
+    // literals of quals and limit
+    fn deparse_statement(
+        &self,
+        table: &str,
+        quals: &[Qual],
+        columns: &[Column],
+        sorts: &[Sort],
+        limit: &Option<Limit>,
+        push_array_quals: bool,
+    ) -> Statement {
+        let fetched_cols = columns
+            .iter()
+            .filter(|c| self.is_fetched(c))
+            .collect::<Vec<_>>();
+        let tgts = if fetched_cols.is_empty() {
             "*".to_string()
         } else {
-            columns
+            fetched_cols
                 .iter()
-                .filter(|c| !self.params.iter().any(|p| p.field == c.name))
-                .map(|c| c.name.clone())
+                .map(|c| {
+                    if is_nested_as_json(c) {
+                        // named tuples are serialized as json objects
+                        format!("toJSONString({0}) as {0}", c.name)
+                    } else if tuple_range_bounds(c).is_some() {
+                        // unnamed tuples and arrays are serialized as json arrays
+                        format!("toJSONString({0}) as {0}", c.name)
+                    } else if is_composite_type(c.type_oid) {
+                        // named tuples are serialized with their element names,
+                        // so the element order can be checked
+                        format!("toJSONString((tupleNames({0}), {0})) as {0}", c.name)
+                    } else if c.type_oid == pg_sys::DATEOID {
+                        // the driver cannot decode Date32, so both Date and
+                        // Date32 are read as 'YYYY-MM-DD' text
+                        format!("toString({0}) as {0}", c.name)
+                    } else if c.type_oid == pg_sys::BYTEAOID {
+                        // bytea is read as the hex encoded bytes of the value's
+                        // text form, which is the serialized state for an
+                        // AggregateFunction column
+                        format!("hex(toString({0})) as {0}", c.name)
+                    } else if let Some(int_type) = enum_int_type(c) {
+                        // enum values are converted to their integer values
+                        format!("CAST({0}, '{1}') as {0}", c.name, int_type)
+                    } else if c.type_oid == pg_sys::TEXTARRAYOID {
+                        // the driver cannot decode arrays of some types, such as
+                        // Enum8, so text array elements are formatted on ClickHouse
+                        format!("arrayMap(x -> toString(x), {0}) as {0}", c.name)
+                    } else if is_text_type(c.type_oid) && !c.options.contains_key("datetime_format")
+                    {
+                        // the driver cannot decode every type, such as UUID, so
+                        // text columns are read as text formatted by ClickHouse
+                        match uuid_case(c) {
+                            Some("upper") => format!("upper(toString({0})) as {0}", c.name),
+                            _ => format!("toString({0}) as {0}", c.name),
+                        }
+                    } else if is_geo_as_json(c) {
+                        // points are unnamed tuples, so the coordinates are
+                        // serialized as nested json arrays
+                        format!(
+                            "concat('{{\"type\":', toJSONString(toTypeName({0})), \
+                             ',\"coordinates\":', toJSONString({0}), '}}') as {0}",
+                            c.name
+                        )
+                    } else if is_variant_as_json(c) {
+                        // the driver cannot decode Variant, so the active type
+                        // and value are serialized on ClickHouse
+                        format!(
+                            "if(variantType({0}) = 'None', null, concat('{{\"type\":', \
+                             toJSONString(toString(variantType({0}))), ',\"value\":', \
+                             toJSONString({0}), '}}')) as {0}",
+                            c.name
+                        )
+                    } else {
+                        c.name.clone()
+                    }
+                })
                 .collect::<Vec<String>>()
                 .join(", ")
         };
 
-        let mut sql = format!("select {} from {}", tgts, &table);
+        let mut head = format!("select {} from {}", tgts, table);
+        if let Some(sample) = &self.sample {
+            head.push_str(&format!(" sample {}", sample));
+        }
 
-        if !quals.is_empty() {
-            let cond = quals
+        let is_local_field = |field: &str| {
+            columns
                 .iter()
-                .filter(|q| !self.params.iter().any(|p| p.field == q.field))
-                .map(|q| q.deparse())
-                .collect::<Vec<String>>()
-                .join(" and ");
-
-            if !cond.is_empty() {
-                sql.push_str(&format!(" where {}", cond));
-            }
-        }
+                .any(|c| c.name == field && is_local_column(c))
+        };
+        let is_partition_field = |field: &str| {
+            columns
+                .iter()
+                .any(|c| c.name == field && is_partition_key(c))
+        };
+        // IN-lists are left to Postgres if they make the query too long, except
+        // the ones on partition keys which prune partitions cheaply
+        let is_pushable = |q: &Qual| {
+            !is_local_field(&q.field)
+                && (push_array_quals
+                    || !matches!(q.value, Value::Array(_))
+                    || is_partition_field(&q.field))
+        };
+        let pushed = quals.iter().map(is_pushable).collect::<Vec<_>>();
+        let all_quals_pushed = pushed.iter().all(|pushed| *pushed);
 
-        // push down sorts
-        if !sorts.is_empty() {
-            let order_by = sorts
+        // push down sorts with explicit nulls order, ClickHouse compares strings
+        // by bytes so sorts with a linguistic collation are left to Postgres to
+        // do locally, so are float sorts whose nulls order can't be matched
+        let sorts_pushable = sorts.iter().all(|sort| {
+            is_pushable_collation(&sort.collate)
+                && is_pushable_null_order(sort, columns)
+                && !is_local_field(&sort.field)
+        });
+        let order_by = (!sorts.is_empty() && sorts_pushable).then(|| {
+            sorts
                 .iter()
                 .map(|sort| sort.deparse())
                 .collect::<Vec<String>>()
-                .join(", ");
+                .join(", ")
+        });
+
+        // the limit cannot be pushed down if the sorts or quals were not,
+        // otherwise we might get a different set of rows
+        let limit_pushed = limit.is_some() && sorts_pushable && all_quals_pushed;
+
+        // the driver cannot decode LowCardinality columns, so let ClickHouse
+        // send them as their plain types, which is common in system tables.
+        // Converted columns are aliased to their own names, so make conditions
+        // and sorts still refer to the original columns.
+        let mut settings = vec![
+            "low_cardinality_allow_in_native_format = 0".to_string(),
+            "prefer_column_name_to_alias = 1".to_string(),
+            // the driver returns extremes as data rows, so never ask for them
+            "extremes = 0".to_string(),
+        ];
+
+        // join_use_nulls makes outer join fill missing columns with null instead
+        // of default values, which turns the columns into Nullable
+        if let Some(join_use_nulls) = self.join_use_nulls {
+            settings.push(format!("join_use_nulls = {}", join_use_nulls as u8));
+        }
+
+        if let Some(max_threads) = self.max_threads {
+            settings.push(format!("max_threads = {}", max_threads));
+        }
+
+        if let Some(format_schema) = &self.format_schema {
+            settings.push(format_schema.clone());
+        }
+
+        settings.extend(self.datetime_settings.iter().cloned());
+
+        // make toJSONString() output nested rows as objects and 64-bit integers
+        // as json numbers
+        if columns
+            .iter()
+            .any(|c| is_nested_as_json(c) || is_variant_as_json(c) || is_composite_type(c.type_oid))
+        {
+            settings.push("output_format_json_named_tuples_as_objects = 1".to_string());
+            settings.push("output_format_json_quote_64bit_integers = 0".to_string());
+        }
+
+        // settings in the settings option take precedence over the ones above
+        let setting_name = |s: &String| s.split(" = ").next().unwrap_or_default().to_owned();
+        let user_names = self
+            .query_settings
+            .iter()
+            .map(setting_name)
+            .collect::<Vec<_>>();
+        settings.retain(|s| !user_names.contains(&setting_name(s)));
+        settings.extend(self.query_settings.iter().cloned());
+
+        Statement {
+            head,
+            pushed,
+            order_by,
+            limit_pushed,
+            settings: format!(" settings {}", settings.join(", ")),
+        }
+    }
+
+    // fill the statement with the literals of the scan's quals and limit
+    fn fill_statement(
+        &mut self,
+        stmt: &Statement,
+        quals: &[Qual],
+        columns: &[Column],
+        limit: &Option<Limit>,
+    ) -> String {
+        let mut sql = stmt.head.clone();
+
+        self.local_quals = quals
+            .iter()
+            .zip(&stmt.pushed)
+            .filter(|(_, pushed)| !**pushed)
+            .map(|(q, _)| q.clone())
+            .collect();
+
+        let mut conds = quals
+            .iter()
+            .zip(&stmt.pushed)
+            .filter(|(q, pushed)| **pushed && !self.params.iter().any(|p| p.field == q.field))
+            .map(|(q, _)| {
+                // citext comparison is case-insensitive, so do the same on ClickHouse
+                let is_citext = columns
+                    .iter()
+                    .any(|c| c.name == q.field && is_citext_type(c.type_oid));
+                let cond = if is_citext {
+                    deparse_citext_qual(q)
+                } else {
+                    deparse_typed_qual(q, self.default_tz).unwrap_or_else(|| q.deparse())
+                };
+                // an IN-list is deparsed to `or` conditions, group them so they
+                // don't mix with the other conditions joined by `and`
+                if q.use_or {
+                    format!("({})", cond)
+                } else {
+                    cond
+                }
+            })
+            .collect::<Vec<String>>();
+        if let Some(watermark) = &self.watermark {
+            conds.extend(watermark.conditions());
+        }
+        if !conds.is_empty() {
+            sql.push_str(&format!(" where {}", conds.join(" and ")));
+        }
+
+        if let Some(order_by) = &stmt.order_by {
             sql.push_str(&format!(" order by {}", order_by));
         }
 
         // push down limits
         // Note: Postgres will take limit and offset locally after reading rows
         // from remote, so we calculate the real limit and only use it without
-        // pushing down offset.
-        if let Some(limit) = limit {
+        // pushing down offset. With the sorts pushed down, this becomes a top-N
+        // query on ClickHouse.
+        self.scan_limit = None;
+        if let (Some(limit), true) = (limit, stmt.limit_pushed) {
             let real_limit = limit.offset + limit.count;
             sql.push_str(&format!(" limit {}", real_limit));
+            self.scan_limit = Some(real_limit as usize);
         }
 
-        Ok(sql)
+        // settings clause must be the last one, after order by and limit
+        sql.push_str(&stmt.settings);
+
+        sql
     }
 }
 
 impl ForeignDataWrapper<ClickHouseFdwError> for ClickHouseFdw {
+    fn error_class(err: &ClickHouseFdwError) -> ErrorClass {
+        err.class()
+    }
+
     fn new(options: &HashMap<String, String>) -> ClickHouseFdwResult<Self> {
         let rt = create_async_runtime()?;
-        let conn_str = match options.get("conn_string") {
-            Some(conn_str) => conn_str.to_owned(),
-            None => {
-                let conn_str_id = require_option("conn_string_id", options)?;
-                get_vault_secret(conn_str_id).unwrap_or_default()
+        let options = &Self::with_profile(options)?;
+        let conn_str = Self::get_conn_str(options)?;
+
+        let retryable_codes = match options.get("retryable_error_codes") {
+            Some(codes) => Self::parse_error_codes(codes)?,
+            None => Self::DEFAULT_RETRYABLE_CODES.to_vec(),
+        };
+
+        let default_tz = match options.get("default_timezone") {
+            Some(tz) => tz.parse::<Tz>().map_err(|_| {
+                ClickHouseFdwError::InvalidOption("default_timezone".to_string(), tz.to_owned())
+            })?,
+            None => Tz::UTC,
+        };
+
+        let datetime_settings = Self::parse_datetime_settings(options)?;
+
+        let query_id_prefix = require_option_or("query_id_prefix", options, "wrappers");
+        if query_id_prefix.is_empty()
+            || !query_id_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(ClickHouseFdwError::InvalidOption(
+                "query_id_prefix".to_string(),
+                query_id_prefix.to_string(),
+            ));
+        }
+
+        let connect_retries = Self::parse_num_option(options, "connect_retries", 0)?;
+        let connect_retry_backoff_ms = Self::parse_num_option(
+            options,
+            "connect_retry_backoff_ms",
+            Self::CONNECT_RETRY_BACKOFF_MS,
+        )?;
+
+        // connection idle timeout and max lifetime in seconds, 0 means unlimited
+        let idle_timeout = match Self::parse_num_option(options, "idle_timeout", 0)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let max_lifetime = match Self::parse_num_option(options, "max_lifetime", 0)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let tcp_nodelay = match options.get("tcp_nodelay").map(|v| v.as_str()) {
+            None => None,
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(v) => {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "tcp_nodelay".to_string(),
+                    v.to_string(),
+                ))
+            }
+        };
+        // TCP keepalive idle time in seconds
+        let tcp_keepalive = match options.get("tcp_keepalive") {
+            None => None,
+            Some(v) => match v.parse::<u64>() {
+                Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                _ => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "tcp_keepalive".to_string(),
+                        v.to_owned(),
+                    ))
+                }
+            },
+        };
+
+        let max_concurrent_scans = match options.get("max_concurrent_scans") {
+            None => None,
+            Some(v) => match v.parse::<usize>() {
+                Ok(n) if n > 0 && n <= semaphore::MAX_SLOTS => Some(n),
+                _ => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "max_concurrent_scans".to_string(),
+                        v.to_owned(),
+                    ))
+                }
+            },
+        };
+        // time in seconds to wait for a scan slot, 0 means waiting indefinitely
+        let acquire_timeout = match Self::parse_num_option(options, "acquire_timeout", 0)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let on_cluster = options
+            .get("on_cluster")
+            .map(|v| parse_cluster_name(v))
+            .transpose()?;
+        let ddl_task_timeout = Self::parse_ddl_task_timeout(options)?;
+
+        let max_query_bytes = match Self::parse_num_option(
+            options,
+            "max_query_bytes",
+            Self::DEFAULT_MAX_QUERY_BYTES,
+        )? {
+            0 => {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "max_query_bytes".to_string(),
+                    "0".to_string(),
+                ))
+            }
+            bytes => bytes,
+        };
+
+        let max_result_mb = match Self::parse_num_option(options, "max_result_mb", 0)? {
+            0 => None,
+            mb => Some(mb),
+        };
+
+        let statement_cache = match options.get("statement_cache").map(|v| v.as_str()) {
+            None | Some("true") => true,
+            Some("false") => false,
+            Some(v) => {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "statement_cache".to_string(),
+                    v.to_string(),
+                ))
             }
         };
 
         stats::inc_stats(Self::FDW_NAME, stats::Metric::CreateTimes, 1);
 
         Ok(Self {
-            rt,
+            rt: Arc::new(rt),
             conn_str,
             client: None,
+            client_opened_at: Instant::now(),
+            client_used_at: Instant::now(),
+            idle_timeout,
+            max_lifetime,
+            tcp_nodelay,
+            tcp_keepalive,
+            max_concurrent_scans,
+            acquire_timeout,
+            session_settings: Vec::new(),
+            role: options.get("role").cloned(),
+            on_cluster,
+            ddl_task_timeout,
             table: String::default(),
             rowid_col: String::default(),
             tgt_cols: Vec::new(),
-            scan_blk: None,
+            table_cols: Vec::new(),
+            scan_result: Vec::new(),
+            row_cnt: 0,
+            fetched_cnt: 0,
             row_idx: 0,
+            scan_limit: None,
             params: Vec::new(),
+            retryable_codes,
+            skip_bad_columns: false,
+            join_use_nulls: None,
+            max_threads: None,
+            fetch_parallelism: None,
+            in_list_batch_size: None,
+            format_schema: None,
+            query_settings: Vec::new(),
+            watermark: None,
+            sample: None,
+            local_quals: Vec::new(),
+            recheck_quals: false,
+            default_tz,
+            datetime_settings,
+            query_id_prefix: query_id_prefix.to_string(),
+            connect_retries,
+            connect_retry_backoff_ms,
+            max_result_mb,
+            result_size: 0,
+            max_query_bytes,
+            surface_warnings: options.get("surface_warnings") == Some(&"true".to_string()),
+            log_server_stats: false,
+            scan_query_ids: Vec::new(),
+            fetch_elapsed: Duration::ZERO,
+            query_label: options
+                .get("query_label")
+                .map(|label| sanitize_comment(label)),
+            sql_rewriter_name: options.get("sql_rewriter").cloned(),
+            sql_rewriter: None,
+            row_callback: None,
+            fixed_string_widths: None,
+            insert_transform: None,
+            transform_columns: None,
+            statement_cache,
+            dedup_token: None,
+            inserted_rows: 0,
+            delete_rowids: Vec::new(),
+            batch_size: Self::DEFAULT_BATCH_SIZE,
+            server_readonly: options.get("server_readonly") == Some(&"true".to_string()),
+            token_name: options.get("token_name").cloned(),
+            token: None,
         })
     }
 
+    fn needs_table_columns(&self, options: &HashMap<String, String>) -> bool {
+        matches!(
+            options.get("on_schema_drift").map(|v| v.as_str()),
+            Some("error" | "warn")
+        )
+    }
+
+    fn set_table_columns(&mut self, columns: &[Column]) {
+        self.table_cols = columns.to_vec();
+    }
+
     fn begin_scan(
         &mut self,
         quals: &[Qual],
@@ -234,81 +3257,398 @@ impl ForeignDataWrapper<ClickHouseFdwError> for ClickHouseFdw {
         limit: &Option<Limit>,
         options: &HashMap<String, String>,
     ) -> ClickHouseFdwResult<()> {
-        self.create_client()?;
+        self.override_conn_str(options)?;
 
         self.table = require_option("table", options)?.to_string();
         self.tgt_cols = columns.to_vec();
         self.row_idx = 0;
+        self.result_size = 0;
+        self.skip_bad_columns = options.get("skip_bad_columns") == Some(&"true".to_string());
+        self.sql_rewriter = self
+            .sql_rewriter_name
+            .as_deref()
+            .map(lookup_sql_rewriter)
+            .transpose()?;
+        self.row_callback = options
+            .get("row_callback")
+            .map(|name| lookup_row_callback(name))
+            .transpose()?;
+        self.join_use_nulls = match options.get("join_use_nulls").map(|v| v.as_str()) {
+            None => None,
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(v) => {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "join_use_nulls".to_string(),
+                    v.to_string(),
+                ))
+            }
+        };
+
+        self.max_threads = match options.get("max_threads") {
+            Some(v) => match v.parse::<u64>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "max_threads".to_string(),
+                        v.to_owned(),
+                    ))
+                }
+            },
+            None => None,
+        };
 
-        let sql = self.deparse(quals, columns, sorts, limit)?;
+        self.fetch_parallelism = match options.get("fetch_parallelism") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "fetch_parallelism".to_string(),
+                        v.to_owned(),
+                    ))
+                }
+            },
+            None => None,
+        };
 
-        if let Some(ref mut client) = self.client {
-            // for simplicity purpose, we fetch whole query result to local,
-            // may need optimization in the future.
-            let block = self.rt.block_on(client.query(&sql).fetch_all())?;
-            stats::inc_stats(
-                Self::FDW_NAME,
-                stats::Metric::RowsIn,
-                block.row_count() as i64,
-            );
-            stats::inc_stats(
-                Self::FDW_NAME,
-                stats::Metric::RowsOut,
-                block.row_count() as i64,
-            );
-            self.scan_blk = Some(block);
+        self.in_list_batch_size = match options.get("in_list_batch_size") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "in_list_batch_size".to_string(),
+                        v.to_owned(),
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        self.format_schema = Self::parse_format_schema(options)?;
+        self.query_settings = match options.get("settings") {
+            Some(v) => parse_query_settings("settings", v)?,
+            None => Vec::new(),
+        };
+        self.recheck_quals = options.get("recheck_quals") == Some(&"true".to_string());
+        self.sample = self.parse_sample_option(options)?;
+        if self.sample.is_none() && columns.iter().any(|c| c.name == Self::SAMPLE_FACTOR_COLUMN) {
+            return Err(ClickHouseFdwError::SampleFactorWithoutSample);
+        }
+        self.log_server_stats = options.get("log_server_stats") == Some(&"true".to_string());
+        self.scan_query_ids.clear();
+        self.fetch_elapsed = Duration::ZERO;
+
+        for col in columns {
+            if is_partition_key(col) && is_local_column(col) {
+                return Err(ClickHouseFdwError::LocalPartitionKey(col.name.to_owned()));
+            }
+            if is_decimal_as_float(col) && col.type_oid != pg_sys::FLOAT8OID {
+                return Err(ClickHouseFdwError::DecimalAsFloatType(col.name.to_owned()));
+            }
+            if let Some(bounds) = tuple_range_bounds(col) {
+                if !matches!(bounds, "[)" | "[]" | "(]" | "()") {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "tuple_as_range".to_string(),
+                        bounds.to_owned(),
+                    ));
+                }
+                if range_subtype(col.type_oid).is_none() {
+                    return Err(ClickHouseFdwError::RangeColumnType(col.name.to_owned()));
+                }
+            }
+            if let Some(case) = uuid_case(col) {
+                if case != "lower" && case != "upper" {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "uuid_case".to_string(),
+                        case.to_owned(),
+                    ));
+                }
+            }
+            if let Some(policy) = col.options.get("unknown_enum_label") {
+                if policy != "error" && policy != "null" {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "unknown_enum_label".to_string(),
+                        policy.to_owned(),
+                    ));
+                }
+            }
+            if let Some(rounding) = col.options.get("subsecond_rounding") {
+                if rounding != "round" && rounding != "truncate" {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "subsecond_rounding".to_string(),
+                        rounding.to_owned(),
+                    ));
+                }
+            }
+            if let Some(fmt) = col.options.get("datetime_format") {
+                if !is_valid_datetime_format(fmt) {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "datetime_format".to_string(),
+                        fmt.to_owned(),
+                    ));
+                }
+            }
+        }
+
+        // read-through cache, ClickHouse is only queried when the cache table
+        // is stale
+        if let Some((cache_table, ttl)) = self.parse_cache_options(options)? {
+            if !cache::is_cache_fresh(&cache_table, ttl) {
+                let _permit = self.acquire_scan_slot()?;
+                self.create_client()?;
+                self.refresh_cache(&cache_table, columns)?;
+            }
+            return self.read_cache(&cache_table, quals, columns);
+        }
+
+        // the whole result is fetched in this function, so the scan slot is
+        // held until it returns
+        let _permit = self.acquire_scan_slot()?;
+        self.create_client()?;
+        self.check_schema_drift(options)?;
+
+        // incremental scan reads only the rows newer than the last watermark
+        self.watermark = None;
+        if let Some(column) = options.get("watermark_column") {
+            if self.table.contains("${") {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "watermark_column".to_string(),
+                    column.to_owned(),
+                ));
+            }
+            let name = options
+                .get("watermark_name")
+                .unwrap_or(&self.table)
+                .to_owned();
+            let last = watermark::get_watermark(&name);
+            match self.fetch_watermark_upper(column, &last)? {
+                Some((upper, value_type)) => {
+                    self.watermark = Some(Watermark {
+                        name,
+                        column: column.to_owned(),
+                        last,
+                        upper,
+                        value_type,
+                        filtered: !quals.is_empty(),
+                    });
+                }
+                None => {
+                    // no new rows since the last watermark
+                    self.scan_result.clear();
+                    self.row_cnt = 0;
+                    return Ok(());
+                }
+            }
+        }
+
+        if options.get("partition_filter_notice") == Some(&"true".to_string()) {
+            let keys = columns
+                .iter()
+                .filter(|c| is_partition_key(c))
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>();
+            if !keys.is_empty() && !quals.iter().any(|q| keys.contains(&q.field.as_str())) {
+                report_notice(&format!(
+                    "ClickHouse scan on '{}' has no condition on partition key {}, all partitions are read",
+                    self.table,
+                    keys.join(", ")
+                ));
+            }
+        }
+
+        let mut sqls = self.deparse_batches(quals, columns, sorts, limit)?;
+        if let Some(sql) = sqls.iter().find(|sql| sql.len() > self.max_query_bytes) {
+            // fall back to filter large IN-lists locally, so the query can
+            // still fit in ClickHouse's max_query_size
+            log_debug1(&format!(
+                "query of {} bytes exceeds max_query_bytes, IN-lists are filtered locally",
+                sql.len()
+            ));
+            let sql = self.deparse(quals, columns, sorts, limit, false)?;
+            if sql.len() > self.max_query_bytes {
+                return Err(ClickHouseFdwError::QueryTooLarge(self.max_query_bytes));
+            }
+            sqls = vec![sql];
+        }
+
+        // for simplicity purpose, we fetch whole query result to local,
+        // may need optimization in the future.
+        let fetch_start = Instant::now();
+        self.query_batches(&sqls)?;
+        self.fetch_elapsed = fetch_start.elapsed();
+
+        if self.surface_warnings {
+            for warning in self.server_warnings()? {
+                report_notice(&format!("ClickHouse warning: {}", warning));
+            }
         }
 
         Ok(())
     }
 
     fn iter_scan(&mut self, row: &mut Row) -> ClickHouseFdwResult<Option<()>> {
-        if let Some(block) = &self.scan_blk {
-            let mut rows = block.rows();
-
-            if let Some(src_row) = rows.nth(self.row_idx) {
-                for tgt_col in &self.tgt_cols {
-                    if let Some(param) = self.params.iter().find(|&p| p.field == tgt_col.name) {
-                        if let Value::Cell(cell) = &param.value {
-                            row.push(&tgt_col.name, Some(cell.clone()));
-                        }
-                        continue;
-                    }
+        loop {
+            if self.row_idx >= self.row_cnt {
+                return Ok(None);
+            }
 
-                    let (i, _) = block
-                        .columns()
-                        .iter()
-                        .enumerate()
-                        .find(|(_, c)| c.name() == tgt_col.name)
-                        .unwrap();
-                    let cell = field_to_cell(&src_row, i)?;
-                    let col_name = src_row.name(i).unwrap();
-                    if cell.as_ref().is_none() {
-                        return Ok(None);
+            for (tgt_col, cells) in self.tgt_cols.iter().zip(self.scan_result.iter_mut()) {
+                match cells {
+                    Some(cells) => row.push(&tgt_col.name, cells[self.row_idx].take()),
+                    None if is_computed(tgt_col) => row.push(&tgt_col.name, None),
+                    None => {
+                        if let Some(param) = self.params.iter().find(|&p| p.field == tgt_col.name) {
+                            if let Value::Cell(cell) = &param.value {
+                                row.push(&tgt_col.name, Some(cell.clone()));
+                            }
+                        }
                     }
-                    row.push(col_name, cell);
                 }
-                self.row_idx += 1;
+            }
+            if let Some(callback) = self.row_callback {
+                call_row_callback(callback, row)?;
+            }
+            self.row_idx += 1;
+
+            // skip rows which don't satisfy the quals left to Postgres, quals
+            // which cannot be evaluated here keep the row
+            if !self.recheck_quals || self.satisfies_local_quals(row) {
                 return Ok(Some(()));
             }
+            row.clear();
         }
-        Ok(None)
     }
 
     fn end_scan(&mut self) -> ClickHouseFdwResult<()> {
-        self.scan_blk.take();
+        // advance the watermark only if all the rows are read, a filtered,
+        // limited or cancelled scan can be run again from the same watermark
+        if let Some(wm) = self.watermark.take() {
+            if !wm.filtered && self.scan_limit.is_none() && self.row_idx >= self.row_cnt {
+                watermark::set_watermark(&wm.name, &wm.upper, &wm.value_type);
+            }
+        }
+        if self.log_server_stats {
+            let stats = self.fetch_scan_stats()?;
+            report_notice(&format!(
+                "ClickHouse scan stats: {} queries, {} rows read, {} bytes read, \
+                 {} ms on server, {} ms to fetch",
+                stats.queries, stats.read_rows, stats.read_bytes, stats.server_ms, stats.fetch_ms
+            ));
+            *LAST_SCAN_STATS.write().unwrap() = Some(stats);
+        }
+        self.scan_result.clear();
+        self.row_cnt = 0;
+        self.release_client();
         Ok(())
     }
 
     fn begin_modify(&mut self, options: &HashMap<String, String>) -> ClickHouseFdwResult<()> {
+        // reject data modification before connecting, so it fails fast instead
+        // of failing on the remote write
+        if self.server_readonly {
+            return Err(ClickHouseFdwError::ReadOnlyServer);
+        }
+
+        let mut insert_settings = Self::parse_insert_settings(options)?;
+        insert_settings.extend(Self::parse_format_schema(options)?);
+
+        self.override_conn_str(options)?;
         self.create_client()?;
+        if self.is_readonly_connection()? {
+            return Err(ClickHouseFdwError::ReadOnlyServer);
+        }
+
+        // the native insert cannot carry query settings, so they are set on the
+        // connection's session, which is only used by this modify. format_schema
+        // is needed to write to Protobuf and Cap'n Proto backed tables as well.
+        self.session_settings = insert_settings;
+        self.apply_session_settings()?;
 
         self.table = require_option("table", options)?.to_string();
         self.rowid_col = require_option("rowid_column", options)?.to_string();
+        if let Some(cluster) = options.get("on_cluster") {
+            self.on_cluster = Some(parse_cluster_name(cluster)?);
+        }
+        if let Some(timeout) = Self::parse_ddl_task_timeout(options)? {
+            self.ddl_task_timeout = Some(timeout);
+        }
+        self.batch_size =
+            match Self::parse_num_option(options, "batch_size", Self::DEFAULT_BATCH_SIZE)? {
+                0 => {
+                    return Err(ClickHouseFdwError::InvalidOption(
+                        "batch_size".to_string(),
+                        "0".to_string(),
+                    ))
+                }
+                size => size,
+            };
+        // the cache table is refreshed on the next scan after the source is
+        // modified
+        if let Some(cache_table) = options
+            .get("cache_table")
+            .and_then(|name| cache::resolve_cache_table(name))
+        {
+            cache::invalidate_cache(&cache_table);
+        }
+
+        // the session parameter overrides the table option, so the key can be
+        // set for each insert statement
+        self.dedup_token = Spi::get_one::<String>(
+            "select nullif(current_setting('clickhouse_fdw.insert_deduplication_token', true), '')",
+        )
+        .ok()
+        .flatten()
+        .or_else(|| options.get("insert_deduplication_token").cloned());
+        self.inserted_rows = 0;
+
+        self.insert_transform = match options.get("insert_transform") {
+            Some(v) if v.trim().is_empty() => {
+                return Err(ClickHouseFdwError::InvalidOption(
+                    "insert_transform".to_string(),
+                    v.to_owned(),
+                ))
+            }
+            v => v.cloned(),
+        };
+        self.transform_columns = None;
+
+        self.fixed_string_widths = None;
+        self.delete_rowids.clear();
         Ok(())
     }
 
     fn insert(&mut self, src: &Row) -> ClickHouseFdwResult<()> {
+        self.recycle_client()?;
+        if self.fixed_string_widths.is_none()
+            && src
+                .cells
+                .iter()
+                .any(|cell| matches!(cell, Some(Cell::Bytea(_))))
+        {
+            let table = self.table.clone();
+            let widths = self
+                .fetch_columns(&table)?
+                .into_iter()
+                .filter_map(|(name, sql_type, ..)| {
+                    fixed_string_width(&sql_type).map(|width| (name, width))
+                })
+                .collect();
+            self.fixed_string_widths = Some(widths);
+        }
+        if let Some(token) = &self.dedup_token {
+            // each row is inserted as a separate block, so its token is derived
+            // from its position, a retried insert of the same rows in the same
+            // order is then deduplicated by ClickHouse
+            let sql = format!(
+                "set insert_deduplication_token = {}",
+                quote_string(&format!("{}_{}", token, self.inserted_rows))
+            );
+            self.execute_on_client(&sql)?;
+        }
+        self.inserted_rows += 1;
+        if let Some(transform) = self.insert_transform.clone() {
+            return self.insert_transformed(&transform, src);
+        }
         if let Some(ref mut client) = self.client {
             let mut row = Vec::new();
             for (col_name, cell) in src.iter() {
@@ -318,20 +3658,59 @@ impl ForeignDataWrapper<ClickHouseFdwError> for ClickHouseFdw {
                         Cell::Bool(v) => row.push((col_name, types::Value::from(*v))),
                         Cell::F64(v) => row.push((col_name, types::Value::from(*v))),
                         Cell::I64(v) => row.push((col_name, types::Value::from(*v))),
-                        Cell::String(v) => row.push((col_name, types::Value::from(v.as_str()))),
-                        Cell::Date(_) => {
-                            let s = cell.to_string().replace('\'', "");
-                            let tm = NaiveDate::parse_from_str(&s, "%Y-%m-%d")?;
-                            let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
-                            let duration = tm - epoch;
-                            let dt = types::Value::Date(duration.num_days() as u16, Tz::UTC);
+                        Cell::String(v) | Cell::Enum(_, v) | Cell::Uuid(v) => {
+                            row.push((col_name, types::Value::from(v.as_str())))
+                        }
+                        Cell::Bytea(v) => {
+                            // bytea is padded with zero bytes to the width of a
+                            // FixedString column, a longer value is rejected
+                            // rather than truncated
+                            let mut v = v.clone();
+                            let width = self
+                                .fixed_string_widths
+                                .as_ref()
+                                .and_then(|widths| widths.get(&col_name));
+                            if let Some(&width) = width {
+                                if v.len() > width {
+                                    return Err(ClickHouseFdwError::BinaryConversionError(format!(
+                                        "value of {} bytes is too long for FixedString({}) column '{}'",
+                                        v.len(),
+                                        width,
+                                        col_name
+                                    )));
+                                }
+                                v.resize(width, 0);
+                            }
+                            row.push((col_name, types::Value::from(v)));
+                        }
+                        Cell::Date(v) => {
+                            // reject dates out of Date's range instead of wrapping them
+                            let days = if v.is_finite() {
+                                u16::try_from(v.to_unix_epoch_days()).ok()
+                            } else {
+                                None
+                            };
+                            let days = days.ok_or_else(|| {
+                                ClickHouseFdwError::DatetimeConversionError(format!(
+                                    "date {} is out of ClickHouse Date range",
+                                    cell
+                                ))
+                            })?;
+                            let dt = types::Value::Date(days, Tz::UTC);
                             row.push((col_name, dt));
                         }
-                        Cell::Timestamp(_) => {
-                            let s = cell.to_string().replace('\'', "");
-                            let tm = NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S")?;
-                            let tm: DateTime<Utc> = DateTime::from_utc(tm, Utc);
-                            row.push((col_name, types::Value::from(tm)));
+                        Cell::Timestamp(v) => {
+                            // timestamp is written in the default timezone, the
+                            // same as it is read from DateTime column
+                            let tm = to_naive_datetime(*v)
+                                .and_then(|tm| self.default_tz.from_local_datetime(&tm).earliest())
+                                .map(|tm| tm.with_timezone(&Utc));
+                            row.push((col_name, datetime_value(tm, cell)?));
+                        }
+                        Cell::Timestamptz(v) => {
+                            let tm =
+                                to_naive_datetime(v.to_utc()).map(|tm| Utc.from_utc_datetime(&tm));
+                            row.push((col_name, datetime_value(tm, cell)?));
                         }
                         _ => {
                             return Err(ClickHouseFdwError::UnsupportedColumnType(cell.to_string()))
@@ -342,13 +3721,17 @@ impl ForeignDataWrapper<ClickHouseFdwError> for ClickHouseFdw {
             let mut block = Block::new();
             block.push(row)?;
 
-            // execute query on ClickHouse
-            self.rt.block_on(client.insert(&self.table, block))?;
+            // execute query on ClickHouse, the insert statement lists the block's
+            // column names so values are matched to ClickHouse columns by name,
+            // not by their position in the foreign table or source table
+            let query = new_query(&self.table, &self.query_label, &self.query_id_prefix);
+            self.rt.block_on(client.insert(query, block))?;
         }
         Ok(())
     }
 
     fn update(&mut self, rowid: &Cell, new_row: &Row) -> ClickHouseFdwResult<()> {
+        self.recycle_client()?;
         if let Some(ref mut client) = self.client {
             let mut sets = Vec::new();
             for (col, cell) in new_row.iter() {
@@ -356,35 +3739,117 @@ impl ForeignDataWrapper<ClickHouseFdwError> for ClickHouseFdw {
                     continue;
                 }
                 if let Some(cell) = cell {
-                    sets.push(format!("{} = {}", col, cell));
+                    let value =
+                        typed_literal(cell, self.default_tz).unwrap_or_else(|| cell.to_string());
+                    sets.push(format!("{} = {}", col, value));
                 } else {
                     sets.push(format!("{} = null", col));
                 }
             }
-            let sql = format!(
-                "alter table {} update {} where {} = {}",
-                self.table,
-                sets.join(", "),
-                self.rowid_col,
-                rowid
+            let sql = alter_table_sql(
+                &self.table,
+                self.on_cluster.as_deref(),
+                self.ddl_task_timeout,
+                &format!(
+                    "update {} where {} = {}",
+                    sets.join(", "),
+                    self.rowid_col,
+                    rowid
+                ),
             );
 
             // execute query on ClickHouse
-            self.rt.block_on(client.execute(&sql))?;
+            let query = new_query(&sql, &self.query_label, &self.query_id_prefix);
+            self.rt.block_on(client.execute(query))?;
         }
         Ok(())
     }
 
     fn delete(&mut self, rowid: &Cell) -> ClickHouseFdwResult<()> {
-        if let Some(ref mut client) = self.client {
-            let sql = format!(
-                "alter table {} delete where {} = {}",
-                self.table, self.rowid_col, rowid
-            );
-
-            // execute query on ClickHouse
-            self.rt.block_on(client.execute(&sql))?;
+        // ClickHouse mutations are heavy, so deleted rows are buffered and
+        // deleted in batches instead of one mutation per row
+        self.delete_rowids.push(rowid.clone());
+        if self.delete_rowids.len() >= self.batch_size {
+            self.flush_deletes()?;
         }
         Ok(())
     }
+
+    fn end_modify(&mut self) -> ClickHouseFdwResult<()> {
+        self.flush_deletes()?;
+        self.release_client();
+        Ok(())
+    }
+
+    fn abort_modify(&mut self) {
+        // the rows of an aborted delete are never deleted, and the connection
+        // is closed without resetting its role
+        self.delete_rowids.clear();
+        self.client = None;
+    }
+
+    fn fdw_routine_hook(routine: &mut FdwRoutine<AllocatedByRust>) {
+        routine.GetForeignRowMarkType = Some(get_foreign_row_mark_type);
+    }
+}
+
+// get options of a foreign server of the ClickHouse wrapper
+fn get_server_options(server: &str) -> ClickHouseFdwResult<HashMap<String, String>> {
+    let sql = "select o.option_name, o.option_value
+               from pg_catalog.pg_foreign_server s
+               join pg_catalog.pg_foreign_data_wrapper w on w.oid = s.srvfdw
+               join pg_catalog.pg_proc p on p.oid = w.fdwhandler
+               left join lateral pg_catalog.pg_options_to_table(s.srvoptions) o on true
+               where s.srvname = $1 and p.proname = 'click_house_fdw_handler'";
+    Spi::connect(|client| {
+        let rows = client
+            .select(
+                sql,
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), server.into_datum())]),
+            )
+            .unwrap();
+        if rows.is_empty() {
+            return Err(ClickHouseFdwError::ServerNotFound(server.to_owned()));
+        }
+        let options = rows
+            .filter_map(|row| {
+                let key = row.get::<String>(1).unwrap()?;
+                let value = row.get::<String>(2).unwrap()?;
+                Some((key, value))
+            })
+            .collect();
+        Ok(options)
+    })
+}
+
+fn fetch_table_columns(server: &str, table: &str) -> ClickHouseFdwResult<Vec<RemoteColumn>> {
+    let options = get_server_options(server)?;
+    let mut fdw = ClickHouseFdw::new(&options)?;
+    fdw.create_client()?;
+    let columns = fdw.fetch_columns(table)?;
+    fdw.release_client();
+    Ok(columns)
+}
+
+// list columns of a ClickHouse table through a foreign server, so tools can
+// build foreign table definitions interactively
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn clickhouse_fdw_columns(
+    server: &str,
+    table: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(column_name, String),
+        name!(data_type, String),
+        name!(is_nullable, bool),
+        name!(default_kind, Option<String>),
+    ),
+> {
+    let columns = fetch_table_columns(server, table)
+        .map_err(ErrorReport::from)
+        .report();
+    TableIterator::new(columns)
 }
@@ -6,6 +6,7 @@ use pgrx::pg_sys::panic::ErrorReport;
 use pgrx::prelude::PgSqlErrorCode;
 use thiserror::Error;
 
+use supabase_wrappers::metrics::ErrorClass;
 use supabase_wrappers::prelude::{CreateRuntimeError, OptionsError};
 
 #[derive(Error, Debug)]
@@ -16,25 +17,156 @@ enum ClickHouseFdwError {
     #[error("unmatched query parameter: {0}")]
     UnmatchedParameter(String),
 
+    #[error("connection profile '{0}' not found")]
+    ProfileNotFound(String),
+
+    #[error("ClickHouse foreign server '{0}' not found")]
+    ServerNotFound(String),
+
+    #[error("invalid value '{1}' for option '{0}'")]
+    InvalidOption(String, String),
+
+    #[error("cache table '{0}' not found")]
+    CacheTableNotFound(String),
+
+    #[error("column '{0}' is not found in cache table '{1}'")]
+    CacheColumnNotFound(String, String),
+
+    #[error("column '{0}' is not found in ClickHouse query result")]
+    ColumnNotFound(String),
+
     #[error("column data type '{0}' is not supported")]
     UnsupportedColumnType(String),
 
+    #[error("column '{0}' with decimal_as_float must be double precision")]
+    DecimalAsFloatType(String),
+
+    #[error("column '{0}' with tuple_as_range must be a range type")]
+    RangeColumnType(String),
+
+    #[error("value '{1}' of column '{0}' is not a pair of bounds of its range subtype")]
+    InvalidRangeValue(String, String),
+
+    #[error("value '{1}' of column '{0}' is not a label of its enum type")]
+    UnknownEnumLabel(String, String),
+
+    #[error("ClickHouse table '{0}' drifted from the foreign table: {1}")]
+    SchemaDrift(String, String),
+
+    #[error("partition key column '{0}' cannot have options which keep its conditions local")]
+    LocalPartitionKey(String),
+
+    #[error("insert_transform output column '{0}' is not found in ClickHouse table")]
+    InsertTransformColumnNotFound(String),
+
+    #[error("column '_sample_factor' is only available with the sample option")]
+    SampleFactorWithoutSample,
+
+    #[error("numeric conversion error: {0}")]
+    NumericConversionError(String),
+
+    #[error("datetime conversion error: {0}")]
+    DatetimeConversionError(String),
+
+    #[error("binary conversion error: {0}")]
+    BinaryConversionError(String),
+
+    #[error("tuple elements ({1}) of column '{0}' don't match composite type fields ({2})")]
+    CompositeTypeMismatch(String, String, String),
+
+    #[error("nested arrays of different lengths cannot be read as a multidimensional array: {0}")]
+    ArrayDimensionMismatch(String),
+
+    #[error("column '{0}' has a value which is not well-formed xml")]
+    InvalidXml(String),
+
+    #[error("json parse error: {0}")]
+    JsonParseError(#[from] serde_json::Error),
+
     #[error("datetime parse error: {0}")]
     DatetimeParseError(#[from] chrono::format::ParseError),
 
+    #[error("row callback error: {0}")]
+    RowCallbackError(String),
+
+    #[error("ClickHouse server is read-only, data modification is not allowed")]
+    ReadOnlyServer,
+
+    #[error("query result exceeds max_result_mb limit of {0} MB")]
+    ResultTooLarge(u64),
+
+    #[error("generated query exceeds max_query_bytes limit of {0} bytes")]
+    QueryTooLarge(usize),
+
+    #[error("timed out waiting for a free ClickHouse scan slot, max_concurrent_scans is {0}")]
+    ScanSlotTimeout(usize),
+
     #[error("{0}")]
     OptionsError(#[from] OptionsError),
 
     #[error("{0}")]
     CreateRuntimeError(#[from] CreateRuntimeError),
 
-    #[error("{0}")]
+    #[error("{}", clickhouse_error_message(.0))]
     ClickHouseError(#[from] clickhouse_rs::errors::Error),
 }
 
+// errors returned by ClickHouse server are marked as such, so they are not
+// mistaken for local failures
+fn clickhouse_error_message(err: &clickhouse_rs::errors::Error) -> String {
+    match err {
+        clickhouse_rs::errors::Error::Server(err) => format!("ClickHouse server error: {}", err),
+        err => err.to_string(),
+    }
+}
+
+impl ClickHouseFdwError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::ClickHouseError(clickhouse_rs::errors::Error::Io(_))
+            | Self::ClickHouseError(clickhouse_rs::errors::Error::Connection(_))
+            | Self::ScanSlotTimeout(_) => ErrorClass::Connection,
+            Self::ClickHouseError(_) | Self::ResultTooLarge(_) | Self::QueryTooLarge(_) => {
+                ErrorClass::Query
+            }
+            Self::UnsupportedColumnType(_)
+            | Self::ColumnNotFound(_)
+            | Self::NumericConversionError(_)
+            | Self::DatetimeConversionError(_)
+            | Self::BinaryConversionError(_)
+            | Self::DatetimeParseError(_)
+            | Self::CompositeTypeMismatch(..)
+            | Self::InvalidXml(_)
+            | Self::ArrayDimensionMismatch(_)
+            | Self::InvalidRangeValue(..)
+            | Self::UnknownEnumLabel(..)
+            | Self::JsonParseError(_) => ErrorClass::Data,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    fn code(&self) -> PgSqlErrorCode {
+        match self {
+            // a requested column missing from the result must not be filled
+            // with another column's values, so it's reported distinctly
+            Self::ColumnNotFound(_) | Self::CacheColumnNotFound(..) => {
+                PgSqlErrorCode::ERRCODE_FDW_INVALID_COLUMN_NAME
+            }
+            // no scan slot is a local resource shortage rather than a failed
+            // query, so it can be told apart to tune max_concurrent_scans
+            Self::ScanSlotTimeout(_) => PgSqlErrorCode::ERRCODE_TOO_MANY_CONNECTIONS,
+            Self::ClickHouseError(clickhouse_rs::errors::Error::Io(_))
+            | Self::ClickHouseError(clickhouse_rs::errors::Error::Connection(_)) => {
+                PgSqlErrorCode::ERRCODE_FDW_UNABLE_TO_ESTABLISH_CONNECTION
+            }
+            _ => PgSqlErrorCode::ERRCODE_FDW_ERROR,
+        }
+    }
+}
+
 impl From<ClickHouseFdwError> for ErrorReport {
     fn from(value: ClickHouseFdwError) -> Self {
-        ErrorReport::new(PgSqlErrorCode::ERRCODE_FDW_ERROR, format!("{value}"), "")
+        ErrorReport::new(value.code(), format!("{value}"), "")
     }
 }
 
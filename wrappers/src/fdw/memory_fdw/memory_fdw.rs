@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use supabase_wrappers::prelude::*;
+
+use super::{MemoryFdwError, MemoryFdwResult};
+
+// In-memory tables, keyed by table name. Postgres backend is single-threaded,
+// so each backend has its own copy of the tables, which live until the backend
+// exits.
+thread_local! {
+    static TABLES: RefCell<HashMap<String, Vec<Row>>> = RefCell::new(HashMap::new());
+}
+
+// A synchronous FDW which keeps data in memory, it doesn't make any network
+// calls so it doesn't need an async runtime.
+#[wrappers_fdw(
+    version = "0.1.0",
+    author = "Supabase",
+    website = "https://github.com/supabase/wrappers/tree/main/wrappers/src/fdw/memory_fdw",
+    error_type = "MemoryFdwError"
+)]
+pub(crate) struct MemoryFdw {
+    table: String,
+    rowid_col: String,
+    tgt_cols: Vec<Column>,
+    scan_result: Vec<Row>,
+    row_idx: usize,
+}
+
+impl MemoryFdw {
+    // find index of the row whose rowid column value matches the rowid
+    fn find_row(rows: &[Row], rowid_col: &str, rowid: &Cell) -> Option<usize> {
+        let rowid = rowid.to_string();
+        rows.iter().position(|row| {
+            row.iter().any(|(col, cell)| {
+                col == rowid_col && cell.as_ref().map(|c| c.to_string()) == Some(rowid.clone())
+            })
+        })
+    }
+}
+
+impl ForeignDataWrapper<MemoryFdwError> for MemoryFdw {
+    fn new(_options: &HashMap<String, String>) -> MemoryFdwResult<Self> {
+        Ok(Self {
+            table: String::default(),
+            rowid_col: String::default(),
+            tgt_cols: Vec::new(),
+            scan_result: Vec::new(),
+            row_idx: 0,
+        })
+    }
+
+    fn begin_scan(
+        &mut self,
+        _quals: &[Qual],
+        columns: &[Column],
+        _sorts: &[Sort],
+        _limit: &Option<Limit>,
+        options: &HashMap<String, String>,
+    ) -> MemoryFdwResult<()> {
+        self.table = require_option("table", options)?.to_string();
+        self.tgt_cols = columns.to_vec();
+        self.row_idx = 0;
+
+        // take a snapshot of the table, so modifications made during the scan
+        // won't affect the scan result
+        self.scan_result = TABLES.with(|tables| {
+            tables
+                .borrow()
+                .get(&self.table)
+                .cloned()
+                .unwrap_or_default()
+        });
+
+        Ok(())
+    }
+
+    fn iter_scan(&mut self, row: &mut Row) -> MemoryFdwResult<Option<()>> {
+        if let Some(src_row) = self.scan_result.get(self.row_idx) {
+            for tgt_col in &self.tgt_cols {
+                let cell = src_row
+                    .iter()
+                    .find(|(col, _)| *col == &tgt_col.name)
+                    .and_then(|(_, cell)| cell.clone());
+                row.push(&tgt_col.name, cell);
+            }
+            self.row_idx += 1;
+            return Ok(Some(()));
+        }
+        Ok(None)
+    }
+
+    fn re_scan(&mut self) -> MemoryFdwResult<()> {
+        self.row_idx = 0;
+        Ok(())
+    }
+
+    fn end_scan(&mut self) -> MemoryFdwResult<()> {
+        self.scan_result.clear();
+        Ok(())
+    }
+
+    fn begin_modify(&mut self, options: &HashMap<String, String>) -> MemoryFdwResult<()> {
+        self.table = require_option("table", options)?.to_string();
+        self.rowid_col = require_option("rowid_column", options)?.to_string();
+        Ok(())
+    }
+
+    fn insert(&mut self, src: &Row) -> MemoryFdwResult<()> {
+        TABLES.with(|tables| {
+            tables
+                .borrow_mut()
+                .entry(self.table.clone())
+                .or_default()
+                .push(src.clone());
+        });
+        Ok(())
+    }
+
+    fn update(&mut self, rowid: &Cell, new_row: &Row) -> MemoryFdwResult<()> {
+        TABLES.with(|tables| {
+            let mut tables = tables.borrow_mut();
+            let rows = tables.entry(self.table.clone()).or_default();
+            let idx = Self::find_row(rows, &self.rowid_col, rowid)
+                .ok_or_else(|| MemoryFdwError::RowIdNotFound(rowid.to_string()))?;
+            let row = &mut rows[idx];
+            for (col, cell) in new_row.iter() {
+                match row.cols.iter().position(|c| c == col) {
+                    Some(i) => row.cells[i] = cell.clone(),
+                    None => row.push(col, cell.clone()),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn delete(&mut self, rowid: &Cell) -> MemoryFdwResult<()> {
+        TABLES.with(|tables| {
+            let mut tables = tables.borrow_mut();
+            let rows = tables.entry(self.table.clone()).or_default();
+            let idx = Self::find_row(rows, &self.rowid_col, rowid)
+                .ok_or_else(|| MemoryFdwError::RowIdNotFound(rowid.to_string()))?;
+            rows.remove(idx);
+            Ok(())
+        })
+    }
+
+    fn end_modify(&mut self) -> MemoryFdwResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+#![allow(clippy::module_inception)]
+mod memory_fdw;
+mod tests;
+
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::PgSqlErrorCode;
+use thiserror::Error;
+
+use supabase_wrappers::prelude::OptionsError;
+
+#[derive(Error, Debug)]
+enum MemoryFdwError {
+    #[error("row with id {0} is not found")]
+    RowIdNotFound(String),
+
+    #[error("{0}")]
+    OptionsError(#[from] OptionsError),
+}
+
+impl From<MemoryFdwError> for ErrorReport {
+    fn from(value: MemoryFdwError) -> Self {
+        ErrorReport::new(PgSqlErrorCode::ERRCODE_FDW_ERROR, format!("{value}"), "")
+    }
+}
+
+type MemoryFdwResult<T> = Result<T, MemoryFdwError>;
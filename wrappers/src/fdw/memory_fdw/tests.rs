@@ -0,0 +1,77 @@
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::pg_test;
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn memory_smoketest() {
+        Spi::connect(|mut c| {
+            c.update(
+                r#"CREATE FOREIGN DATA WRAPPER memory_wrapper
+                     HANDLER memory_fdw_handler VALIDATOR memory_fdw_validator"#,
+                None,
+                None,
+            )
+            .unwrap();
+            c.update(
+                r#"CREATE SERVER my_memory_server
+                     FOREIGN DATA WRAPPER memory_wrapper"#,
+                None,
+                None,
+            )
+            .unwrap();
+            c.update(
+                r#"
+                  CREATE FOREIGN TABLE test_table (
+                    id bigint,
+                    name text
+                  )
+                  SERVER my_memory_server
+                  OPTIONS (
+                    table 'test_table',
+                    rowid_column 'id'
+                  )
+             "#,
+                None,
+                None,
+            )
+            .unwrap();
+
+            let results = c.select("SELECT * FROM test_table", None, None).unwrap();
+            assert_eq!(results.len(), 0);
+
+            c.update(
+                "INSERT INTO test_table (id, name) VALUES (1, 'foo'), (2, 'bar'), (3, 'baz')",
+                None,
+                None,
+            )
+            .unwrap();
+            let results = c
+                .select(
+                    "SELECT name FROM test_table WHERE id > 1 ORDER BY id",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .filter_map(|r| r.get_by_name::<&str, _>("name").unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(results, vec!["bar", "baz"]);
+
+            c.update(
+                "UPDATE test_table SET name = 'qux' WHERE id = 2",
+                None,
+                None,
+            )
+            .unwrap();
+            c.update("DELETE FROM test_table WHERE id = 3", None, None)
+                .unwrap();
+            let results = c
+                .select("SELECT name FROM test_table ORDER BY id", None, None)
+                .unwrap()
+                .filter_map(|r| r.get_by_name::<&str, _>("name").unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(results, vec!["foo", "qux"]);
+        });
+    }
+}
@@ -1,6 +1,9 @@
 #[cfg(feature = "helloworld_fdw")]
 mod helloworld_fdw;
 
+#[cfg(feature = "memory_fdw")]
+mod memory_fdw;
+
 #[cfg(feature = "bigquery_fdw")]
 mod bigquery_fdw;
 
@@ -21,3 +24,6 @@ mod s3_fdw;
 
 #[cfg(feature = "logflare_fdw")]
 mod logflare_fdw;
+
+#[cfg(feature = "cassandra_fdw")]
+mod cassandra_fdw;
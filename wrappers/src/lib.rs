@@ -5,8 +5,17 @@ pg_module_magic!();
 extension_sql_file!("../sql/bootstrap.sql", bootstrap);
 extension_sql_file!("../sql/finalize.sql", finalize);
 
+mod cache;
 mod fdw;
+mod profile;
 mod stats;
+mod watermark;
+
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+    supabase_wrappers::metrics::init();
+    supabase_wrappers::semaphore::init();
+}
 
 #[cfg(test)]
 pub mod pg_test {
@@ -0,0 +1,49 @@
+use pgrx::prelude::*;
+use std::collections::HashMap;
+
+// connection profile table name
+const PROFILE_TABLE: &str = "wrappers_connection_profiles";
+
+// get profile table full qualified name
+fn get_profile_table() -> String {
+    let sql = format!(
+        "select b.nspname || '.{}'
+         from pg_catalog.pg_extension a join pg_namespace b on a.extnamespace = b.oid
+         where a.extname = 'wrappers'",
+        PROFILE_TABLE
+    );
+    Spi::get_one(&sql)
+        .unwrap()
+        .unwrap_or_else(|| panic!("cannot find connection profile table '{}'", PROFILE_TABLE))
+}
+
+// get options of a connection profile, return None if the profile doesn't exist
+#[allow(dead_code)]
+pub(crate) fn get_profile_options(name: &str) -> Option<HashMap<String, String>> {
+    let sql = format!(
+        "select o.key, o.value
+         from {} p left join lateral jsonb_each_text(p.options) o on true
+         where p.name = $1",
+        get_profile_table()
+    );
+    Spi::connect(|client| {
+        let rows = client
+            .select(
+                &sql,
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())]),
+            )
+            .unwrap();
+        if rows.is_empty() {
+            return None;
+        }
+        let options = rows
+            .filter_map(|row| {
+                let key = row.get::<String>(1).unwrap()?;
+                let value = row.get::<String>(2).unwrap()?;
+                Some((key, value))
+            })
+            .collect();
+        Some(options)
+    })
+}
@@ -111,3 +111,40 @@ pub(crate) fn set_metadata(fdw_name: &str, metadata: Option<JsonB>) {
     )
     .unwrap();
 }
+
+// get metrics of all FDWs, which are counted in shared memory if the extension
+// is loaded by shared_preload_libraries, otherwise in current backend only
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+fn wrappers_stats() -> TableIterator<
+    'static,
+    (
+        name!(fdw_name, String),
+        name!(connections_opened, i64),
+        name!(queries_run, i64),
+        name!(rows_read, i64),
+        name!(rows_written, i64),
+        name!(connection_errors, i64),
+        name!(query_errors, i64),
+        name!(data_errors, i64),
+        name!(other_errors, i64),
+    ),
+> {
+    let rows = supabase_wrappers::metrics::snapshot()
+        .into_iter()
+        .map(|(fdw_name, c)| {
+            (
+                fdw_name,
+                c.connections_opened,
+                c.queries_run,
+                c.rows_read,
+                c.rows_written,
+                c.connection_errors,
+                c.query_errors,
+                c.data_errors,
+                c.other_errors,
+            )
+        })
+        .collect::<Vec<_>>();
+    TableIterator::new(rows)
+}
@@ -0,0 +1,65 @@
+use pgrx::prelude::*;
+
+// watermark table name
+const WATERMARK_TABLE: &str = "wrappers_watermarks";
+
+// get watermark table full qualified name
+fn get_watermark_table() -> String {
+    let sql = format!(
+        "select b.nspname || '.{}'
+         from pg_catalog.pg_extension a join pg_namespace b on a.extnamespace = b.oid
+         where a.extname = 'wrappers'",
+        WATERMARK_TABLE
+    );
+    Spi::get_one(&sql)
+        .unwrap()
+        .unwrap_or_else(|| panic!("cannot find watermark table '{}'", WATERMARK_TABLE))
+}
+
+// get the watermark value and its type name, return None if the watermark
+// doesn't exist yet
+#[allow(dead_code)]
+pub(crate) fn get_watermark(name: &str) -> Option<(String, String)> {
+    let sql = format!(
+        "select value, value_type from {} where name = $1",
+        get_watermark_table()
+    );
+    Spi::connect(|client| {
+        let rows = client
+            .select(
+                &sql,
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())]),
+            )
+            .unwrap();
+        if rows.is_empty() {
+            return None;
+        }
+        let (value, value_type) = rows.first().get_two::<String, String>().unwrap();
+        value.zip(value_type)
+    })
+}
+
+// set the watermark value and its type name, the watermark is advanced in the
+// current transaction so it is rolled back with the transaction
+#[allow(dead_code)]
+pub(crate) fn set_watermark(name: &str, value: &str, value_type: &str) {
+    let sql = format!(
+        "insert into {} as w (name, value, value_type) values($1, $2, $3)
+         on conflict(name)
+         do update set
+            value = excluded.value,
+            value_type = excluded.value_type,
+            updated_at = timezone('utc'::text, now())",
+        get_watermark_table()
+    );
+    Spi::run_with_args(
+        &sql,
+        Some(vec![
+            (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), value.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), value_type.into_datum()),
+        ]),
+    )
+    .unwrap();
+}